@@ -0,0 +1,128 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Cr0, Cr4, Efer, Segment, SpecialRegisters};
+use crate::{VirtualCpu, VirtualMachine};
+
+use std::io::{ErrorKind, Result};
+
+const PRESENT: u64 = 1 << 0;
+const WRITEABLE: u64 = 1 << 1;
+const LARGE: u64 = 1 << 7;
+
+/// The selectors of the GDT written by `protected_mode()` and `long_mode()`.
+pub const CODE64_SELECTOR: u16 = 0x08;
+pub const DATA_SELECTOR: u16 = 0x10;
+pub const CODE32_SELECTOR: u16 = 0x18;
+
+/// The guest memory needed at `addr` by `protected_mode()`.
+pub const PROTECTED_MODE_SIZE: u64 = 0x1000;
+
+/// The guest memory needed at `addr` by `long_mode()`: the GDT, a PML4, a
+/// PDPT and four page directories identity mapping the first 4 GiB.
+pub const LONG_MODE_SIZE: u64 = 0x7000;
+
+fn write_gdt(vm: &mut VirtualMachine, sregs: &mut SpecialRegisters, addr: u64) -> Result<()> {
+    if addr & 0xfff != 0 {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+
+    let data = Segment::data(DATA_SELECTOR);
+    let gdt = [
+        0,
+        Segment::code64(CODE64_SELECTOR).descriptor(),
+        data.descriptor(),
+        Segment::code32(CODE32_SELECTOR).descriptor(),
+    ];
+
+    let gdt: Vec<u8> = gdt.iter().flat_map(|d| d.to_le_bytes().to_vec()).collect();
+    vm.write_memory(addr, &gdt)?;
+
+    sregs.gdt.base = addr;
+    sregs.gdt.limit = gdt.len() as u16 - 1;
+
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+
+    Ok(())
+}
+
+/// Puts a fresh vCPU into flat 32-bit protected mode, without paging.
+///
+/// A GDT is written to the page at guest physical address `addr`. Returns
+/// the special registers that were set.
+pub fn protected_mode(
+    vm: &mut VirtualMachine,
+    cpu: &mut VirtualCpu,
+    addr: u64,
+) -> Result<SpecialRegisters> {
+    let mut sregs = cpu.special_registers()?;
+
+    write_gdt(vm, &mut sregs, addr)?;
+    sregs.cs = Segment::code32(CODE32_SELECTOR);
+    sregs.cr0 |= Cr0::PE.bits();
+
+    cpu.set_special_registers(sregs)?;
+    Ok(sregs)
+}
+
+/// Puts a fresh vCPU into 64-bit long mode with the first 4 GiB identity
+/// mapped using 2 MiB pages.
+///
+/// The GDT and page tables are written to `LONG_MODE_SIZE` bytes at guest
+/// physical address `addr`. Returns the special registers that were set.
+pub fn long_mode(
+    vm: &mut VirtualMachine,
+    cpu: &mut VirtualCpu,
+    addr: u64,
+) -> Result<SpecialRegisters> {
+    let mut sregs = cpu.special_registers()?;
+
+    write_gdt(vm, &mut sregs, addr)?;
+    sregs.cs = Segment::code64(CODE64_SELECTOR);
+
+    let pml4 = addr + 0x1000;
+    let pdpt = addr + 0x2000;
+    let pd = addr + 0x3000;
+
+    // Whole pages are written, so that stale entries do not map anything.
+    let mut page = vec![0u8; 0x1000];
+    page[..8].copy_from_slice(&(pdpt | PRESENT | WRITEABLE).to_le_bytes());
+    vm.write_memory(pml4, &page)?;
+
+    let mut page = vec![0u8; 0x1000];
+    for i in 0..4 {
+        let entry = (pd + i * 0x1000) | PRESENT | WRITEABLE;
+        page[i as usize * 8..][..8].copy_from_slice(&entry.to_le_bytes());
+    }
+    vm.write_memory(pdpt, &page)?;
+
+    let mut entries = Vec::with_capacity(4 * 512 * 8);
+    for i in 0..4 * 512u64 {
+        let entry = (i << 21) | PRESENT | WRITEABLE | LARGE;
+        entries.extend_from_slice(&entry.to_le_bytes());
+    }
+    vm.write_memory(pd, &entries)?;
+
+    sregs.cr3 = pml4;
+    sregs.cr4 |= Cr4::PAE.bits();
+    sregs.cr0 |= (Cr0::PE | Cr0::PG).bits();
+    sregs.efer |= (Efer::LME | Efer::LMA).bits();
+
+    cpu.set_special_registers(sregs)?;
+    Ok(sregs)
+}
@@ -0,0 +1,251 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{DescriptorTable, PhysicalMemory, Segment};
+
+use std::io::{ErrorKind, Result};
+
+const CODE: u8 = 0xb;
+const DATA: u8 = 0x3;
+const TSS: u8 = 0xb;
+
+impl Segment {
+    fn flat(selector: u16, kind: u8, long: bool) -> Self {
+        Segment {
+            base: 0,
+            limit: 0xffff_ffff,
+            selector,
+            kind,
+            present: 1,
+            dpl: (selector & 3) as u8,
+            db: !long as u8,
+            s: 1,
+            l: long as u8,
+            g: 1,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
+        }
+    }
+
+    /// A flat 64-bit code segment. The DPL is taken from the selector's RPL.
+    pub fn code64(selector: u16) -> Self {
+        Self::flat(selector, CODE, true)
+    }
+
+    /// A flat 32-bit code segment. The DPL is taken from the selector's RPL.
+    pub fn code32(selector: u16) -> Self {
+        Self::flat(selector, CODE, false)
+    }
+
+    /// A flat read/write data segment. The DPL is taken from the selector's
+    /// RPL.
+    pub fn data(selector: u16) -> Self {
+        Self::flat(selector, DATA, false)
+    }
+
+    /// A busy 64-bit TSS, which is what the CPU expects to find in TR.
+    pub fn tss(selector: u16, base: u64, limit: u32) -> Self {
+        Segment {
+            base,
+            limit,
+            selector,
+            kind: TSS,
+            present: 1,
+            dpl: 0,
+            db: 0,
+            s: 0,
+            l: 0,
+            g: 0,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
+        }
+    }
+
+    /// Encodes the segment as an 8-byte GDT or LDT entry.
+    ///
+    /// System segments only keep the low 32 bits of their base here; use
+    /// `system_descriptor()` for them in long mode.
+    pub fn descriptor(&self) -> u64 {
+        let limit = if self.g != 0 {
+            self.limit >> 12
+        } else {
+            self.limit
+        } as u64;
+        let base = self.base;
+
+        (limit & 0xffff)
+            | (base & 0xff_ffff) << 16
+            | (self.kind as u64 & 0xf) << 40
+            | (self.s as u64 & 1) << 44
+            | (self.dpl as u64 & 3) << 45
+            | (self.present as u64 & 1) << 47
+            | (limit >> 16 & 0xf) << 48
+            | (self.avl as u64 & 1) << 52
+            | (self.l as u64 & 1) << 53
+            | (self.db as u64 & 1) << 54
+            | (self.g as u64 & 1) << 55
+            | (base >> 24 & 0xff) << 56
+    }
+
+    /// Encodes a system segment (TSS or LDT) as a 16-byte long mode GDT entry.
+    pub fn system_descriptor(&self) -> [u64; 2] {
+        [self.descriptor(), self.base >> 32]
+    }
+
+    /// Decodes an 8-byte GDT or LDT entry.
+    pub fn from_descriptor(selector: u16, desc: u64) -> Self {
+        let g = (desc >> 55 & 1) as u8;
+        let limit = (desc & 0xffff | (desc >> 48 & 0xf) << 16) as u32;
+        let present = (desc >> 47 & 1) as u8;
+
+        Segment {
+            base: desc >> 16 & 0xff_ffff | (desc >> 56 & 0xff) << 24,
+            limit: if g != 0 { limit << 12 | 0xfff } else { limit },
+            selector,
+            kind: (desc >> 40 & 0xf) as u8,
+            present,
+            dpl: (desc >> 45 & 3) as u8,
+            db: (desc >> 54 & 1) as u8,
+            s: (desc >> 44 & 1) as u8,
+            l: (desc >> 53 & 1) as u8,
+            g,
+            avl: (desc >> 52 & 1) as u8,
+            unusable: (present == 0) as u8,
+            padding: 0,
+        }
+    }
+
+    /// Decodes a 16-byte long mode system segment entry.
+    pub fn from_system_descriptor(selector: u16, desc: [u64; 2]) -> Self {
+        let mut seg = Self::from_descriptor(selector, desc[0]);
+        seg.base |= (desc[1] & 0xffff_ffff) << 32;
+        seg
+    }
+
+    /// Looks `selector` up in a guest's GDT.
+    ///
+    /// The table base is treated as a guest physical address. In long mode,
+    /// system segments are read as 16-byte entries.
+    pub fn from_gdt<M: PhysicalMemory + ?Sized>(
+        mem: &M,
+        gdt: &DescriptorTable,
+        selector: u16,
+        long: bool,
+    ) -> Result<Self> {
+        let index = selector as u64 & !7;
+        if index == 0 || selector & 4 != 0 || index + 7 > gdt.limit as u64 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let mut buf = [0u8; 8];
+        mem.read_physical(gdt.base + index, &mut buf)?;
+        let low = u64::from_le_bytes(buf);
+
+        if !long || low >> 44 & 1 != 0 {
+            return Ok(Self::from_descriptor(selector, low));
+        }
+
+        if index + 15 > gdt.limit as u64 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        mem.read_physical(gdt.base + index + 8, &mut buf)?;
+        let high = u64::from_le_bytes(buf);
+        Ok(Self::from_system_descriptor(selector, [low, high]))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GateKind {
+    Interrupt = 0xe,
+    Trap = 0xf,
+}
+
+/// An IDT entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Gate {
+    pub kind: GateKind,
+    pub selector: u16,
+    pub offset: u64,
+    pub dpl: u8,
+    /// Interrupt stack table slot; only used in long mode.
+    pub ist: u8,
+    pub present: bool,
+}
+
+impl Gate {
+    pub fn interrupt(selector: u16, offset: u64) -> Self {
+        Gate {
+            kind: GateKind::Interrupt,
+            selector,
+            offset,
+            dpl: 0,
+            ist: 0,
+            present: true,
+        }
+    }
+
+    pub fn trap(selector: u16, offset: u64) -> Self {
+        Gate {
+            kind: GateKind::Trap,
+            ..Self::interrupt(selector, offset)
+        }
+    }
+
+    /// Encodes the gate as an 8-byte 32-bit protected mode IDT entry.
+    pub fn descriptor(&self) -> u64 {
+        let offset = self.offset;
+
+        (offset & 0xffff)
+            | (self.selector as u64) << 16
+            | (self.ist as u64 & 7) << 32
+            | (self.kind as u64) << 40
+            | (self.dpl as u64 & 3) << 45
+            | (self.present as u64) << 47
+            | (offset >> 16 & 0xffff) << 48
+    }
+
+    /// Encodes the gate as a 16-byte long mode IDT entry.
+    pub fn long_descriptor(&self) -> [u64; 2] {
+        [self.descriptor(), self.offset >> 32 & 0xffff_ffff]
+    }
+
+    /// Decodes an 8-byte IDT entry. Returns `None` for task gates and other
+    /// types that are not interrupt or trap gates.
+    pub fn from_descriptor(desc: u64) -> Option<Self> {
+        let kind = match desc >> 40 & 0xf {
+            0xe => GateKind::Interrupt,
+            0xf => GateKind::Trap,
+            _ => return None,
+        };
+
+        Some(Gate {
+            kind,
+            selector: (desc >> 16) as u16,
+            offset: desc & 0xffff | (desc >> 48 & 0xffff) << 16,
+            dpl: (desc >> 45 & 3) as u8,
+            ist: (desc >> 32 & 7) as u8,
+            present: desc >> 47 & 1 != 0,
+        })
+    }
+
+    /// Decodes a 16-byte long mode IDT entry.
+    pub fn from_long_descriptor(desc: [u64; 2]) -> Option<Self> {
+        let mut gate = Self::from_descriptor(desc[0])?;
+        gate.offset |= (desc[1] & 0xffff_ffff) << 32;
+        Some(gate)
+    }
+}
@@ -12,8 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_arch = "x86_64")]
+mod boot;
+#[cfg(target_arch = "x86_64")]
+mod descriptor;
+#[cfg(target_arch = "x86_64")]
+mod paging;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+#[cfg(target_arch = "x86_64")]
+pub use boot::*;
+#[cfg(target_arch = "x86_64")]
+pub use descriptor::*;
+#[cfg(target_arch = "x86_64")]
+pub use paging::*;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::*;
@@ -0,0 +1,112 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Cr0, Cr4, Efer, SpecialRegisters};
+
+use std::io::{ErrorKind, Result};
+
+const PRESENT: u64 = 1 << 0;
+const WRITEABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const LARGE: u64 = 1 << 7;
+const ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+/// The result of translating a guest virtual address.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Translation {
+    pub physical_address: u64,
+    pub valid: bool,
+    pub writeable: bool,
+    pub usermode: bool,
+}
+
+/// Guest physical memory that page tables can be read from.
+pub trait PhysicalMemory {
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+/// A memory image starting at guest physical address 0.
+impl PhysicalMemory for [u8] {
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let start = addr as usize;
+        let src = self
+            .get(start..start + buf.len())
+            .ok_or(ErrorKind::InvalidInput)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+/// Translates `gva` by walking the guest's 4-level or 5-level page tables.
+///
+/// Unlike `VirtualCpu::translate()`, this needs nothing but the special
+/// registers and the guest memory, so it also works on saved state.
+pub fn walk<M>(mem: &M, sregs: &SpecialRegisters, gva: u64) -> Result<Translation>
+where
+    M: PhysicalMemory + ?Sized,
+{
+    if !sregs.cr0().contains(Cr0::PG) {
+        return Ok(Translation {
+            physical_address: gva,
+            valid: true,
+            writeable: true,
+            usermode: true,
+        });
+    }
+
+    if !sregs.efer().contains(Efer::LMA) {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+
+    let levels = if sregs.cr4().contains(Cr4::LA57) {
+        5
+    } else {
+        4
+    };
+    let mut table = sregs.cr3 & ADDRESS;
+    let mut writeable = true;
+    let mut usermode = true;
+
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level;
+        let index = (gva >> shift) & 0x1ff;
+
+        let mut entry = [0u8; 8];
+        mem.read_physical(table + index * 8, &mut entry)?;
+        let entry = u64::from_le_bytes(entry);
+
+        if entry & PRESENT == 0 {
+            return Ok(Translation::default());
+        }
+
+        writeable &= entry & WRITEABLE != 0;
+        usermode &= entry & USER != 0;
+
+        // 1 GiB and 2 MiB pages end the walk early.
+        if level == 0 || (level < 3 && entry & LARGE != 0) {
+            let size = 1u64 << shift;
+
+            return Ok(Translation {
+                physical_address: (entry & ADDRESS & !(size - 1)) | (gva & (size - 1)),
+                valid: true,
+                writeable,
+                usermode,
+            });
+        }
+
+        table = entry & ADDRESS;
+    }
+
+    unreachable!()
+}
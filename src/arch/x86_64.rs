@@ -14,6 +14,8 @@
 
 use bitflags::bitflags;
 
+use std::io::{Error, ErrorKind, Result};
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Registers {
@@ -38,7 +40,7 @@ pub struct Registers {
 }
 
 #[repr(C)]
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
 pub struct Segment {
     pub base: u64,
     pub limit: u32,
@@ -155,8 +157,487 @@ pub struct DebugExit {
     pub dr7: u64,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DebugRegisters {
+    pub db: [u64; 4],
+    pub dr6: u64,
+    pub dr7: u64,
+    pub flags: u64,
+    pub reserved: [u64; 9],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+/// A hardware breakpoint or watchpoint. `len` must be 1, 2, 4 or 8 and is
+/// always 1 for `BreakpointKind::Execute`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u64,
+    pub kind: BreakpointKind,
+    pub len: u8,
+}
+
+/// Guest debugging configuration for `VirtualCpu::set_guest_debug()`.
+///
+/// With `software_breakpoints`, `int3` instructions in the guest exit
+/// with `Reason::Debug` (exception 3) instead of being delivered to it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct GuestDebug {
+    pub single_step: bool,
+    pub software_breakpoints: bool,
+    pub breakpoints: [Option<Breakpoint>; 4],
+}
+
 bitflags! {
     pub struct RunFlags: u16 {
         const SMM = 1 << 0;
     }
 }
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IrqchipId {
+    PicMaster = 0,
+    PicSlave = 1,
+    Ioapic = 2,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Irqchip {
+    PicMaster(PicState),
+    PicSlave(PicState),
+    Ioapic(IoapicState),
+}
+
+impl Irqchip {
+    pub fn id(&self) -> IrqchipId {
+        match self {
+            Irqchip::PicMaster(..) => IrqchipId::PicMaster,
+            Irqchip::PicSlave(..) => IrqchipId::PicSlave,
+            Irqchip::Ioapic(..) => IrqchipId::Ioapic,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PicState {
+    pub last_irr: u8,
+    pub irr: u8,
+    pub imr: u8,
+    pub isr: u8,
+    pub priority_add: u8,
+    pub irq_base: u8,
+    pub read_reg_select: u8,
+    pub poll: u8,
+    pub special_mask: u8,
+    pub init_state: u8,
+    pub auto_eoi: u8,
+    pub rotate_on_auto_eoi: u8,
+    pub special_fully_nested_mode: u8,
+    pub init4: u8,
+    pub elcr: u8,
+    pub elcr_mask: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IoapicState {
+    pub base_address: u64,
+    pub ioregsel: u32,
+    pub id: u32,
+    pub irr: u32,
+    pub pad: u32,
+    pub redirtbl: [u64; 24],
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct PitConfigFlags: u32 {
+        const SPEAKER_DUMMY = 1 << 0;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PitConfig {
+    pub flags: PitConfigFlags,
+    pub pad: [u32; 15],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PitChannelState {
+    pub count: u32,
+    pub latched_count: u16,
+    pub count_latched: u8,
+    pub status_latched: u8,
+    pub status: u8,
+    pub read_state: u8,
+    pub write_state: u8,
+    pub write_latch: u8,
+    pub rw_mode: u8,
+    pub mode: u8,
+    pub bcd: u8,
+    pub gate: u8,
+    pub count_load_time: i64,
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct PitStateFlags: u32 {
+        const HPET_LEGACY = 1 << 0;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PitState {
+    pub channels: [PitChannelState; 3],
+    pub flags: PitStateFlags,
+    pub reserved: [u32; 9],
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct ClockFlags: u32 {
+        const TSC_STABLE = 1 << 1;
+        const REALTIME = 1 << 2;
+        const HOST_TSC = 1 << 3;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ClockData {
+    pub clock: u64,
+    pub flags: ClockFlags,
+    pub pad0: u32,
+    pub realtime: u64,
+    pub host_tsc: u64,
+    pub pad: [u32; 4],
+}
+
+bitflags! {
+    /// The MSR accesses that exit to userspace instead of injecting #GP.
+    pub struct MsrExitReasons: u64 {
+        const INVALID = 1 << 0;
+        const UNKNOWN = 1 << 1;
+        const FILTER = 1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct MsrAccess: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MsrFilterRange {
+    pub access: MsrAccess,
+    pub base: u32,
+    pub count: u32,
+    pub bitmap: Vec<u8>,
+}
+
+/// Selects the MSRs that the guest may access directly. Accesses that are
+/// denied exit with `Reason::MsrRead` or `Reason::MsrWrite`.
+///
+/// Ranges are matched in the order they were added; MSRs outside of all
+/// ranges get the default action.
+#[derive(Debug, Clone)]
+pub struct MsrFilter {
+    pub(crate) default_deny: bool,
+    pub(crate) ranges: Vec<MsrFilterRange>,
+}
+
+impl MsrFilter {
+    /// The most ranges a filter can hold.
+    pub const MAX_RANGES: usize = 16;
+
+    /// The most MSRs a single range can cover.
+    pub const MAX_MSRS: u32 = 0x600 * 8;
+
+    /// Creates a filter that allows MSRs outside of its ranges.
+    pub fn allow() -> Self {
+        MsrFilter {
+            default_deny: false,
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Creates a filter that denies MSRs outside of its ranges.
+    pub fn deny() -> Self {
+        MsrFilter {
+            default_deny: true,
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Adds a range from a bitmap with one bit per MSR, set if allowed.
+    /// `VirtualMachine::set_msr_filter()` fails if the bitmap is shorter than
+    /// `count` bits, or `count` is above `MAX_MSRS`.
+    pub fn range(mut self, base: u32, count: u32, access: MsrAccess, bitmap: Vec<u8>) -> Self {
+        self.ranges.push(MsrFilterRange {
+            access,
+            base,
+            count,
+            bitmap,
+        });
+        self
+    }
+
+    /// Allows `count` MSRs starting at `base`.
+    pub fn allow_range(self, base: u32, count: u32, access: MsrAccess) -> Self {
+        let bitmap = vec![0xff; (count as usize).div_ceil(8)];
+        self.range(base, count, access, bitmap)
+    }
+
+    /// Denies `count` MSRs starting at `base`.
+    pub fn deny_range(self, base: u32, count: u32, access: MsrAccess) -> Self {
+        let bitmap = vec![0x00; (count as usize).div_ceil(8)];
+        self.range(base, count, access, bitmap)
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct Cr0: u64 {
+        const PE = 1 << 0;
+        const MP = 1 << 1;
+        const EM = 1 << 2;
+        const TS = 1 << 3;
+        const ET = 1 << 4;
+        const NE = 1 << 5;
+        const WP = 1 << 16;
+        const AM = 1 << 18;
+        const NW = 1 << 29;
+        const CD = 1 << 30;
+        const PG = 1 << 31;
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct Cr4: u64 {
+        const VME = 1 << 0;
+        const PVI = 1 << 1;
+        const TSD = 1 << 2;
+        const DE = 1 << 3;
+        const PSE = 1 << 4;
+        const PAE = 1 << 5;
+        const MCE = 1 << 6;
+        const PGE = 1 << 7;
+        const PCE = 1 << 8;
+        const OSFXSR = 1 << 9;
+        const OSXMMEXCPT = 1 << 10;
+        const UMIP = 1 << 11;
+        const LA57 = 1 << 12;
+        const VMXE = 1 << 13;
+        const SMXE = 1 << 14;
+        const FSGSBASE = 1 << 16;
+        const PCIDE = 1 << 17;
+        const OSXSAVE = 1 << 18;
+        const SMEP = 1 << 20;
+        const SMAP = 1 << 21;
+        const PKE = 1 << 22;
+        const CET = 1 << 23;
+        const PKS = 1 << 24;
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct Efer: u64 {
+        const SCE = 1 << 0;
+        const LME = 1 << 8;
+        const LMA = 1 << 10;
+        const NXE = 1 << 11;
+        const SVME = 1 << 12;
+        const LMSLE = 1 << 13;
+        const FFXSR = 1 << 14;
+        const TCE = 1 << 15;
+    }
+}
+
+bitflags! {
+    pub struct Rflags: u64 {
+        const CF = 1 << 0;
+        /// Always set.
+        const FIXED = 1 << 1;
+        const PF = 1 << 2;
+        const AF = 1 << 4;
+        const ZF = 1 << 6;
+        const SF = 1 << 7;
+        const TF = 1 << 8;
+        const IF = 1 << 9;
+        const DF = 1 << 10;
+        const OF = 1 << 11;
+        const IOPL = 3 << 12;
+        const NT = 1 << 14;
+        const RF = 1 << 16;
+        const VM = 1 << 17;
+        const AC = 1 << 18;
+        const VIF = 1 << 19;
+        const VIP = 1 << 20;
+        const ID = 1 << 21;
+    }
+}
+
+impl Default for Rflags {
+    fn default() -> Self {
+        Rflags::FIXED
+    }
+}
+
+bitflags! {
+    pub struct XCr0: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREG = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+    }
+}
+
+impl Default for XCr0 {
+    fn default() -> Self {
+        XCr0::X87
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+impl Registers {
+    pub fn rflags(&self) -> Rflags {
+        Rflags::from_bits_truncate(self.rflags)
+    }
+
+    pub fn set_rflags(&mut self, rflags: Rflags) {
+        self.rflags = rflags.bits();
+    }
+
+    /// Checks for values that the CPU would refuse to enter the guest with.
+    pub fn validate(&self) -> Result<()> {
+        if self.rflags & !Rflags::all().bits() != 0 {
+            return Err(invalid("reserved RFLAGS bits set"));
+        }
+
+        if !self.rflags().contains(Rflags::FIXED) {
+            return Err(invalid("RFLAGS bit 1 must be set"));
+        }
+
+        Ok(())
+    }
+}
+
+impl SpecialRegisters {
+    pub fn cr0(&self) -> Cr0 {
+        Cr0::from_bits_truncate(self.cr0)
+    }
+
+    pub fn set_cr0(&mut self, cr0: Cr0) {
+        self.cr0 = cr0.bits();
+    }
+
+    pub fn cr4(&self) -> Cr4 {
+        Cr4::from_bits_truncate(self.cr4)
+    }
+
+    pub fn set_cr4(&mut self, cr4: Cr4) {
+        self.cr4 = cr4.bits();
+    }
+
+    pub fn efer(&self) -> Efer {
+        Efer::from_bits_truncate(self.efer)
+    }
+
+    pub fn set_efer(&mut self, efer: Efer) {
+        self.efer = efer.bits();
+    }
+
+    /// Checks for combinations that the CPU would refuse to enter the guest
+    /// with.
+    pub fn validate(&self) -> Result<()> {
+        let cr0 = self.cr0();
+        let cr4 = self.cr4();
+        let efer = self.efer();
+
+        if self.cr0 >> 32 != 0 {
+            return Err(invalid("reserved CR0 bits set"));
+        }
+
+        if cr0.contains(Cr0::PG) && !cr0.contains(Cr0::PE) {
+            return Err(invalid("CR0.PG requires CR0.PE"));
+        }
+
+        if cr0.contains(Cr0::NW) && !cr0.contains(Cr0::CD) {
+            return Err(invalid("CR0.NW requires CR0.CD"));
+        }
+
+        let long = efer.contains(Efer::LME) && cr0.contains(Cr0::PG);
+
+        if long && !cr4.contains(Cr4::PAE) {
+            return Err(invalid("long mode requires CR4.PAE"));
+        }
+
+        if long != efer.contains(Efer::LMA) {
+            return Err(invalid("EFER.LMA must match EFER.LME and CR0.PG"));
+        }
+
+        if self.cs.l != 0 && !efer.contains(Efer::LMA) {
+            return Err(invalid("CS.L requires EFER.LMA"));
+        }
+
+        if cr4.contains(Cr4::PCIDE) && !long {
+            return Err(invalid("CR4.PCIDE requires long mode"));
+        }
+
+        if long && self.cs.l != 0 && self.cs.db != 0 {
+            return Err(invalid("CS.L and CS.D cannot both be set"));
+        }
+
+        Ok(())
+    }
+}
+
+impl XCr0 {
+    /// Checks for combinations that `xsetbv` would refuse with #GP.
+    pub fn validate(&self) -> Result<()> {
+        let avx512 = XCr0::OPMASK | XCr0::ZMM_HI256 | XCr0::HI16_ZMM;
+
+        if !self.contains(XCr0::X87) {
+            return Err(invalid("XCR0.X87 must be set"));
+        }
+
+        if self.contains(XCr0::AVX) && !self.contains(XCr0::SSE) {
+            return Err(invalid("XCR0.AVX requires XCR0.SSE"));
+        }
+
+        if self.contains(XCr0::BNDREG) != self.contains(XCr0::BNDCSR) {
+            return Err(invalid("XCR0.BNDREG and XCR0.BNDCSR must match"));
+        }
+
+        if self.intersects(avx512) && (!self.contains(avx512) || !self.contains(XCr0::AVX)) {
+            return Err(invalid(
+                "AVX-512 state requires all of its bits and XCR0.AVX",
+            ));
+        }
+
+        Ok(())
+    }
+}
@@ -15,12 +15,51 @@
 use super::*;
 
 use crate::util::fd::Fd;
+use crate::util::ioctl;
 use crate::{arch, run};
 
-use std::io::Result;
+use std::io::{ErrorKind, Result};
 use std::mem::{size_of, size_of_val};
 use std::os::raw::{c_int, c_ulong};
 use std::os::unix::io::FromRawFd;
+use std::ptr::read_volatile;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct GuestDebug {
+    control: u32,
+    pad: u32,
+    debugreg: [u64; 8],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Translation {
+    linear_address: u64,
+    physical_address: u64,
+    valid: u8,
+    writeable: u8,
+    usermode: u8,
+    pad: [u8; 5],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Xcr {
+    xcr: u32,
+    reserved: u32,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Xcrs {
+    nr_xcrs: u32,
+    flags: u32,
+    xcrs: [Xcr; 16],
+    padding: [u64; 16],
+}
 
 impl VirtualCpu {
     pub fn new(vm: &VirtualMachine) -> Result<Self> {
@@ -35,7 +74,20 @@ impl VirtualCpu {
             .file(&fd, 0)
             .done()?;
 
-        Ok(Self { fd, run })
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let ring = match vm.coalesced_mmio as usize * page {
+            0 => None,
+            o if o + page > vm.vcpu_mmap_size => None,
+            o => Some(o),
+        };
+
+        Ok(Self {
+            fd,
+            run,
+            ring,
+            coalesced: run::Coalesced::default(),
+            pending: false,
+        })
     }
 
     pub fn registers(&self) -> Result<arch::Registers> {
@@ -51,6 +103,8 @@ impl VirtualCpu {
     pub fn set_registers(&mut self, regs: arch::Registers) -> Result<()> {
         const KVM_SET_REGS: c_ulong = 1083223682;
 
+        regs.validate()?;
+
         unsafe {
             self.fd.ioctl(KVM_SET_REGS, &regs)?;
         }
@@ -70,19 +124,209 @@ impl VirtualCpu {
     pub fn set_special_registers(&mut self, regs: arch::SpecialRegisters) -> Result<()> {
         const KVM_SET_SREGS: c_ulong = 1094233732;
 
+        regs.validate()?;
+
         unsafe {
             self.fd.ioctl(KVM_SET_SREGS, &regs)?;
         }
         Ok(())
     }
 
+    pub fn xcr0(&self) -> Result<arch::XCr0> {
+        let mut xcrs = Xcrs::default();
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_XCRS, &mut xcrs)?;
+        }
+
+        let xcr0 = xcrs.xcrs[..xcrs.nr_xcrs as usize]
+            .iter()
+            .find(|x| x.xcr == 0);
+        Ok(arch::XCr0::from_bits_truncate(xcr0.map_or(1, |x| x.value)))
+    }
+
+    pub fn set_xcr0(&mut self, xcr0: arch::XCr0) -> Result<()> {
+        xcr0.validate()?;
+
+        let mut xcrs = Xcrs {
+            nr_xcrs: 1,
+            ..Default::default()
+        };
+        xcrs.xcrs[0].value = xcr0.bits();
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_XCRS, &xcrs)?;
+        }
+        Ok(())
+    }
+
+    /// Translates a guest virtual address using the vCPU's current mode.
+    pub fn translate(&self, gva: u64) -> Result<arch::Translation> {
+        let mut tr = Translation {
+            linear_address: gva,
+            ..Default::default()
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_TRANSLATE, &mut tr)?;
+        }
+
+        Ok(arch::Translation {
+            physical_address: tr.physical_address,
+            valid: tr.valid != 0,
+            writeable: tr.writeable != 0,
+            usermode: tr.usermode != 0,
+        })
+    }
+
+    pub fn set_guest_debug(&mut self, config: &arch::GuestDebug) -> Result<()> {
+        const KVM_GUESTDBG_ENABLE: u32 = 0x0000_0001;
+        const KVM_GUESTDBG_SINGLESTEP: u32 = 0x0000_0002;
+        const KVM_GUESTDBG_USE_SW_BP: u32 = 0x0001_0000;
+        const KVM_GUESTDBG_USE_HW_BP: u32 = 0x0002_0000;
+
+        let mut dbg = GuestDebug::default();
+
+        if config.single_step {
+            dbg.control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP;
+        }
+
+        if config.software_breakpoints {
+            dbg.control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+        }
+
+        for (i, bp) in config.breakpoints.iter().enumerate() {
+            let bp = match bp {
+                Some(bp) => bp,
+                None => continue,
+            };
+
+            let rw = match bp.kind {
+                arch::BreakpointKind::Execute => 0b00,
+                arch::BreakpointKind::Write => 0b01,
+                arch::BreakpointKind::ReadWrite => 0b11,
+            };
+
+            let len = match (bp.kind, bp.len) {
+                (_, 1) => 0b00,
+                (arch::BreakpointKind::Execute, _) => return Err(ErrorKind::InvalidInput.into()),
+                (_, 2) => 0b01,
+                (_, 4) => 0b11,
+                (_, 8) => 0b10,
+                _ => return Err(ErrorKind::InvalidInput.into()),
+            };
+
+            // Global enable, R/W and LEN fields of DR7.
+            dbg.debugreg[i] = bp.addr;
+            dbg.debugreg[7] |= 0b10 << (i * 2) | (rw | len << 2) << (16 + i * 4);
+            dbg.control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+        }
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_GUEST_DEBUG, &dbg)?;
+        }
+        Ok(())
+    }
+
+    pub fn debug_registers(&self) -> Result<arch::DebugRegisters> {
+        let mut regs = arch::DebugRegisters::default();
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_DEBUGREGS, &mut regs)?;
+        }
+        Ok(regs)
+    }
+
+    pub fn set_debug_registers(&mut self, regs: arch::DebugRegisters) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_DEBUGREGS, &regs)?;
+        }
+        Ok(())
+    }
+
+    pub fn tsc_khz(&self) -> Result<u32> {
+        unsafe { self.fd.ioctl(ioctl::KVM_GET_TSC_KHZ, ()) }
+    }
+
+    pub fn set_tsc_khz(&mut self, khz: u32) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_TSC_KHZ, khz as c_ulong)?;
+        }
+        Ok(())
+    }
+
+    /// Tells the guest that it was paused, so that its watchdogs do not
+    /// mistake the lost time for a soft lockup.
+    pub fn kvmclock_ctrl(&mut self) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_KVMCLOCK_CTRL, ())?;
+        }
+        Ok(())
+    }
+
+    /// Injects an external interrupt, as from a userspace PIC.
+    pub fn interrupt(&mut self, vector: u8) -> Result<()> {
+        let irq = vector as u32;
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_INTERRUPT, &irq)?;
+        }
+        Ok(())
+    }
+
+    pub fn ready_for_interrupt(&self) -> bool {
+        self.run.ready_for_interrupt_injection
+    }
+
+    /// Requests a `Reason::InterruptWindow` exit once the guest can accept
+    /// an external interrupt.
+    pub fn request_interrupt_window(&mut self, request: bool) {
+        self.run.request_interrupt_window = request;
+    }
+
+    /// Injects #GP for the `Reason::MsrRead` or `Reason::MsrWrite` that
+    /// was just returned, instead of completing the access.
+    pub fn reject_msr(&mut self) -> Result<()> {
+        // Anything else returned since the exit would be corrupted. A pending
+        // exit means that a buffered write was returned after it.
+        match self.run.exit_reason {
+            run::ReasonCode::X86Rdmsr | run::ReasonCode::X86Wrmsr if !self.pending => {
+                self.run.reason.msr.error = 1;
+                Ok(())
+            }
+
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+
     pub fn run<'b>(&'b mut self) -> Result<Reason<'b>> {
         const KVM_RUN: c_ulong = 44672;
 
-        unsafe {
-            self.fd.ioctl(KVM_RUN, 0)?;
+        if !self.pending {
+            unsafe {
+                self.fd.ioctl(KVM_RUN, 0)?;
+            }
+            self.pending = true;
         }
 
+        // Writes buffered in the coalesced ring happened before this exit.
+        if self.pop_coalesced() {
+            let c = &self.coalesced;
+            let data = &c.data[..c.len as usize];
+
+            return Ok(match c.pio {
+                0 => Reason::Mmio {
+                    addr: c.phys_addr,
+                    data,
+                    read: false,
+                },
+
+                _ => Reason::Io(ReasonIo::Out {
+                    port: c.phys_addr as u16,
+                    data,
+                }),
+            });
+        }
+
+        self.pending = false;
+
         Ok(match (*self.run).exit_reason {
             run::ReasonCode::Hlt => Reason::Halt,
 
@@ -124,7 +368,67 @@ impl VirtualCpu {
                 }
             }
 
+            run::ReasonCode::Debug => Reason::Debug(unsafe { self.run.reason.debug }),
+
+            run::ReasonCode::IrqWindowOpen => Reason::InterruptWindow,
+
+            run::ReasonCode::X86Rdmsr => {
+                let msr = unsafe { &mut self.run.reason.msr };
+                Reason::MsrRead {
+                    index: msr.index,
+                    data: &mut msr.data,
+                }
+            }
+
+            run::ReasonCode::X86Wrmsr => {
+                let msr = unsafe { &self.run.reason.msr };
+                Reason::MsrWrite {
+                    index: msr.index,
+                    value: msr.data,
+                }
+            }
+
+            run::ReasonCode::IoapicEoi => {
+                let eoi = unsafe { &self.run.reason.eoi };
+                Reason::IoapicEoi { vector: eoi.vector }
+            }
+
             r => panic!("Unsupported exit reason: {:?}", r),
         })
     }
+
+    fn pop_coalesced(&mut self) -> bool {
+        let offset = match self.ring {
+            Some(o) => o - size_of::<run::Run>(),
+            None => return false,
+        };
+
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let max = (page - size_of::<run::CoalescedRing>()) / size_of::<run::Coalesced>();
+
+        let ring = self.run[offset..].as_ptr() as *const run::CoalescedRing;
+        let entries = unsafe { ring.add(1) as *const run::Coalesced };
+
+        // The ring is shared by all vCPUs of the VM, so claim entries atomically.
+        let first = unsafe { &*(&(*ring).first as *const u32 as *const AtomicU32) };
+        let last = unsafe { &*(&(*ring).last as *const u32 as *const AtomicU32) };
+
+        loop {
+            let head = first.load(Ordering::Acquire);
+            if head == last.load(Ordering::Acquire) {
+                return false;
+            }
+
+            let entry = unsafe { read_volatile(entries.add(head as usize)) };
+            let next = (head + 1) % max as u32;
+
+            if first
+                .compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                self.coalesced = entry;
+                return true;
+            }
+        }
+    }
 }
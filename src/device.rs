@@ -0,0 +1,178 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! KVM devices created with `VirtualMachine::create_device()`.
+
+use crate::util::fd::Fd;
+use crate::util::ioctl;
+
+use std::io::Result;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    /// The KVM-VFIO bridge, which tells KVM about VFIO groups in use.
+    Vfio = 4,
+}
+
+/// An attribute, identified by its group and its id within the group.
+///
+/// # Safety
+///
+/// The kernel reads and writes the attribute through a pointer to a
+/// `Value`, so `Value` must have exactly the layout the kernel expects.
+pub unsafe trait Attribute {
+    type Value: Copy + Default;
+
+    fn group(&self) -> u32;
+    fn attr(&self) -> u64;
+}
+
+/// An untyped attribute for device kinds without a typed wrapper.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Raw<T> {
+    group: u32,
+    attr: u64,
+    value: PhantomData<T>,
+}
+
+impl<T> Raw<T> {
+    /// # Safety
+    ///
+    /// The kernel must read and write attribute `attr` of `group` as a `T`.
+    pub unsafe fn new(group: u32, attr: u64) -> Self {
+        Raw {
+            group,
+            attr,
+            value: PhantomData,
+        }
+    }
+}
+
+unsafe impl<T: Copy + Default> Attribute for Raw<T> {
+    type Value = T;
+
+    fn group(&self) -> u32 {
+        self.group
+    }
+
+    fn attr(&self) -> u64 {
+        self.attr
+    }
+}
+
+/// Attributes of the KVM-VFIO bridge (`Kind::Vfio`).
+///
+/// The value of both attributes is the file descriptor of a VFIO group,
+/// as a `c_int`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Vfio {
+    GroupAdd,
+    GroupDel,
+}
+
+unsafe impl Attribute for Vfio {
+    type Value = c_int;
+
+    fn group(&self) -> u32 {
+        const KVM_DEV_VFIO_GROUP: u32 = 1;
+        KVM_DEV_VFIO_GROUP
+    }
+
+    fn attr(&self) -> u64 {
+        match self {
+            Vfio::GroupAdd => 1,
+            Vfio::GroupDel => 2,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DeviceAttr {
+    flags: u32,
+    group: u32,
+    attr: u64,
+    addr: u64,
+}
+
+impl DeviceAttr {
+    fn new(attr: &impl Attribute, addr: u64) -> Self {
+        DeviceAttr {
+            flags: 0,
+            group: attr.group(),
+            attr: attr.attr(),
+            addr,
+        }
+    }
+}
+
+pub struct Device {
+    pub(crate) fd: Fd,
+    pub(crate) kind: Kind,
+}
+
+impl Device {
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn set_attr<A: Attribute>(&self, attr: &A, value: &A::Value) -> Result<()> {
+        let attr = DeviceAttr::new(attr, value as *const A::Value as u64);
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_DEVICE_ATTR, &attr)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_attr<A: Attribute>(&self, attr: &A) -> Result<A::Value> {
+        let mut value = A::Value::default();
+        let attr = DeviceAttr::new(attr, &mut value as *mut A::Value as u64);
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_DEVICE_ATTR, &attr)?;
+        }
+        Ok(value)
+    }
+
+    pub fn has_attr(&self, attr: &impl Attribute) -> Result<bool> {
+        let attr = DeviceAttr::new(attr, 0);
+
+        match unsafe { self.fd.ioctl(ioctl::KVM_HAS_DEVICE_ATTR, &attr) } {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds a VFIO group to a `Kind::Vfio` device.
+    pub fn vfio_group_add(&self, group: &impl AsRawFd) -> Result<()> {
+        self.set_attr(&Vfio::GroupAdd, &(group.as_raw_fd() as c_int))
+    }
+
+    /// Removes a VFIO group from a `Kind::Vfio` device.
+    pub fn vfio_group_del(&self, group: &impl AsRawFd) -> Result<()> {
+        self.set_attr(&Vfio::GroupDel, &(group.as_raw_fd() as c_int))
+    }
+}
+
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,534 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A GDB remote serial protocol stub.
+//!
+//! The stub does not own the run loop. Call `Stub::stopped()` whenever the
+//! vCPU should be inspected: once before the first `VirtualCpu::run()` and
+//! then for every `Reason::Debug`. It talks to `gdb` until the guest is to
+//! be resumed and arms single-stepping and breakpoints accordingly.
+//!
+//! Memory addresses from `gdb` are treated as guest physical addresses.
+//!
+//! ```no_run
+//! # use ketuvim::*;
+//! # fn f(vm: &mut VirtualMachine, cpu: &mut VirtualCpu) -> std::io::Result<()> {
+//! let mut stub = gdb::Stub::new(gdb::accept_tcp("127.0.0.1:1234")?);
+//! let mut resume = stub.stopped(vm, cpu, None)?;
+//!
+//! while resume == gdb::Resume::Running {
+//!     match cpu.run()? {
+//!         Reason::Debug(exit) => resume = stub.stopped(vm, cpu, Some(exit))?,
+//!         Reason::Halt => break,
+//!         _ => { /* handle I/O */ }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{arch, VirtualCpu, VirtualMachine};
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+const SIGTRAP: u8 = 5;
+const INT3: u8 = 0xcc;
+
+/// The largest packet we accept, advertised to `gdb` in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Waits for `gdb` to connect with `target remote <addr>`.
+pub fn accept_tcp(addr: impl ToSocketAddrs) -> Result<TcpStream> {
+    let (stream, _) = TcpListener::bind(addr)?.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Waits for `gdb` to connect with `target remote <path>`.
+pub fn accept_unix(path: impl AsRef<Path>) -> Result<UnixStream> {
+    let (stream, _) = UnixListener::bind(path)?.accept()?;
+    Ok(stream)
+}
+
+/// What the caller should do after `Stub::stopped()` returns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resume {
+    /// Keep running the vCPU.
+    Running,
+
+    /// `gdb` detached; breakpoints have been removed.
+    Detached,
+
+    /// `gdb` asked to kill the guest.
+    Killed,
+}
+
+pub struct Stub<S: Read + Write> {
+    stream: S,
+    software: Vec<(u64, u8)>,
+    hardware: [Option<arch::Breakpoint>; 4],
+    stop: String,
+}
+
+impl<S: Read + Write> Stub<S> {
+    pub fn new(stream: S) -> Self {
+        Stub {
+            stream,
+            software: Vec::new(),
+            hardware: [None; 4],
+            stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// Reports a stop to `gdb` and serves its requests until it resumes
+    /// the guest, detaches or kills it.
+    pub fn stopped(
+        &mut self,
+        vm: &mut VirtualMachine,
+        cpu: &mut VirtualCpu,
+        exit: Option<arch::DebugExit>,
+    ) -> Result<Resume> {
+        let initial = exit.is_none();
+        self.stop = self.stop_reply(exit);
+
+        if !initial {
+            let stop = self.stop.clone();
+            self.send(&stop)?;
+        }
+
+        loop {
+            let packet = self.receive()?;
+            let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+            let reply = match cmd {
+                "?" => Ok(self.stop.clone()),
+                "g" => self.read_registers(cpu),
+                "G" => self.write_registers(cpu, args),
+                "p" => self.read_register(cpu, args),
+                "P" => self.write_register(cpu, args),
+                "m" => self.read_memory(vm, args),
+                "M" => self.write_memory(vm, args),
+                "Z" => self.insert(vm, args),
+                "z" => self.remove(vm, args),
+                "H" | "T" => Ok("OK".into()),
+
+                "c" => return self.resume(cpu, false),
+                "s" => return self.resume(cpu, true),
+
+                "v" if args == "Cont?" => Ok("vCont;c;C;s;S".into()),
+                "v" if args.starts_with("Cont;c") || args.starts_with("Cont;C") => {
+                    return self.resume(cpu, false);
+                }
+                "v" if args.starts_with("Cont;s") || args.starts_with("Cont;S") => {
+                    return self.resume(cpu, true);
+                }
+
+                "q" if args.starts_with("Supported") => Ok(format!(
+                    "PacketSize={:x};swbreak+;hwbreak+;vContSupported+",
+                    PACKET_SIZE
+                )),
+                "q" if args == "Attached" => Ok("1".into()),
+                "q" if args == "C" => Ok("QC1".into()),
+                "q" if args == "fThreadInfo" => Ok("m1".into()),
+                "q" if args == "sThreadInfo" => Ok("l".into()),
+
+                "D" => {
+                    self.detach(vm, cpu)?;
+                    self.send("OK")?;
+                    return Ok(Resume::Detached);
+                }
+
+                "k" => return Ok(Resume::Killed),
+
+                _ => Ok(String::new()),
+            };
+
+            // Malformed packets get an error reply instead of ending the session.
+            let reply = match reply {
+                Err(e) if e.kind() == ErrorKind::InvalidData => "E01".into(),
+                reply => reply?,
+            };
+
+            self.send(&reply)?;
+        }
+    }
+
+    fn stop_reply(&self, exit: Option<arch::DebugExit>) -> String {
+        const BP_VECTOR: u32 = 3;
+
+        let exit = match exit {
+            Some(exit) => exit,
+            None => return format!("S{:02x}", SIGTRAP),
+        };
+
+        if exit.exception == BP_VECTOR {
+            return format!("T{:02x}swbreak:;", SIGTRAP);
+        }
+
+        // DR6.B0 - DR6.B3 tell which hardware breakpoint was hit.
+        for (i, bp) in self.hardware.iter().enumerate() {
+            if let Some(bp) = bp {
+                if exit.dr6 & (1 << i) != 0 {
+                    return match bp.kind {
+                        arch::BreakpointKind::Execute => format!("T{:02x}hwbreak:;", SIGTRAP),
+                        arch::BreakpointKind::Write => {
+                            format!("T{:02x}watch:{:x};", SIGTRAP, bp.addr)
+                        }
+                        arch::BreakpointKind::ReadWrite => {
+                            format!("T{:02x}awatch:{:x};", SIGTRAP, bp.addr)
+                        }
+                    };
+                }
+            }
+        }
+
+        format!("S{:02x}", SIGTRAP)
+    }
+
+    fn resume(&mut self, cpu: &mut VirtualCpu, single_step: bool) -> Result<Resume> {
+        cpu.set_guest_debug(&arch::GuestDebug {
+            single_step,
+            software_breakpoints: !self.software.is_empty(),
+            breakpoints: self.hardware,
+        })?;
+
+        Ok(Resume::Running)
+    }
+
+    fn detach(&mut self, vm: &mut VirtualMachine, cpu: &mut VirtualCpu) -> Result<()> {
+        for (addr, byte) in self.software.drain(..) {
+            vm.write_memory(addr, &[byte])?;
+        }
+
+        self.hardware = [None; 4];
+        cpu.set_guest_debug(&arch::GuestDebug::default())
+    }
+
+    fn read_registers(&self, cpu: &VirtualCpu) -> Result<String> {
+        let mut reply = String::new();
+
+        for n in 0..REGISTERS {
+            reply += &register(cpu, n)?;
+        }
+
+        Ok(reply)
+    }
+
+    fn write_registers(&self, cpu: &mut VirtualCpu, args: &str) -> Result<String> {
+        let mut regs = cpu.registers()?;
+        let mut offset = 0;
+
+        // Segment selectors are read-only: their hidden parts can't be set.
+        for n in 0..GPRS {
+            let width = width(n) * 2;
+            let value = args.get(offset..offset + width).ok_or_else(invalid)?;
+            *gpr(&mut regs, n) = decode_le(value)?;
+            offset += width;
+        }
+
+        if regs.validate().is_err() {
+            return Ok("E22".into());
+        }
+
+        cpu.set_registers(regs)?;
+        Ok("OK".into())
+    }
+
+    fn read_register(&self, cpu: &VirtualCpu, args: &str) -> Result<String> {
+        let n = usize::from_str_radix(args, 16).map_err(|_| invalid())?;
+        if n >= REGISTERS {
+            return Ok("E01".into());
+        }
+
+        register(cpu, n)
+    }
+
+    fn write_register(&self, cpu: &mut VirtualCpu, args: &str) -> Result<String> {
+        let mut parts = args.splitn(2, '=');
+        let n = parts.next().ok_or_else(invalid)?;
+        let n = usize::from_str_radix(n, 16).map_err(|_| invalid())?;
+        let value = decode_le(parts.next().ok_or_else(invalid)?)?;
+
+        if n >= GPRS {
+            return Ok("E01".into());
+        }
+
+        let mut regs = cpu.registers()?;
+        *gpr(&mut regs, n) = value;
+        if regs.validate().is_err() {
+            return Ok("E22".into());
+        }
+
+        cpu.set_registers(regs)?;
+        Ok("OK".into())
+    }
+
+    fn read_memory(&self, vm: &VirtualMachine, args: &str) -> Result<String> {
+        let (addr, len) = address_length(args)?;
+        if len > PACKET_SIZE / 2 {
+            return Ok("E22".into());
+        }
+
+        let mut buf = vec![0; len];
+
+        if vm.read_memory(addr, &mut buf).is_err() {
+            return Ok("E14".into());
+        }
+
+        // Hide our own breakpoint instructions.
+        for (bp, byte) in &self.software {
+            if *bp >= addr && *bp < addr + len as u64 {
+                buf[(*bp - addr) as usize] = *byte;
+            }
+        }
+
+        Ok(encode(&buf))
+    }
+
+    fn write_memory(&self, vm: &mut VirtualMachine, args: &str) -> Result<String> {
+        let mut parts = args.splitn(2, ':');
+        let (addr, len) = address_length(parts.next().ok_or_else(invalid)?)?;
+        let data = decode(parts.next().ok_or_else(invalid)?)?;
+
+        if data.len() != len || vm.write_memory(addr, &data).is_err() {
+            return Ok("E14".into());
+        }
+
+        Ok("OK".into())
+    }
+
+    fn insert(&mut self, vm: &mut VirtualMachine, args: &str) -> Result<String> {
+        let (kind, addr, len) = breakpoint(args)?;
+
+        let kind = match kind {
+            0 => {
+                if !self.software.iter().any(|(a, _)| *a == addr) {
+                    let mut byte = [0];
+                    if vm.read_memory(addr, &mut byte).is_err() {
+                        return Ok("E14".into());
+                    }
+
+                    vm.write_memory(addr, &[INT3])?;
+                    self.software.push((addr, byte[0]));
+                }
+
+                return Ok("OK".into());
+            }
+
+            1 => arch::BreakpointKind::Execute,
+            2 => arch::BreakpointKind::Write,
+            3 | 4 => arch::BreakpointKind::ReadWrite,
+            _ => return Ok(String::new()),
+        };
+
+        // Debug registers watch 1, 2, 4 or 8 naturally aligned bytes.
+        let len = match (kind, u8::try_from(len)) {
+            (arch::BreakpointKind::Execute, _) => 1,
+            (_, Ok(len @ 1)) | (_, Ok(len @ 2)) | (_, Ok(len @ 4)) | (_, Ok(len @ 8))
+                if addr % len as u64 == 0 =>
+            {
+                len
+            }
+            _ => return Ok("E22".into()),
+        };
+
+        match self.hardware.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => {
+                *slot = Some(arch::Breakpoint { addr, kind, len });
+                Ok("OK".into())
+            }
+
+            None => Ok("E28".into()),
+        }
+    }
+
+    fn remove(&mut self, vm: &mut VirtualMachine, args: &str) -> Result<String> {
+        let (kind, addr, _) = breakpoint(args)?;
+
+        if kind == 0 {
+            if let Some(i) = self.software.iter().position(|(a, _)| *a == addr) {
+                let (addr, byte) = self.software.remove(i);
+                vm.write_memory(addr, &[byte])?;
+            }
+
+            return Ok("OK".into());
+        }
+
+        for slot in self.hardware.iter_mut() {
+            if matches!(slot, Some(bp) if bp.addr == addr) {
+                *slot = None;
+            }
+        }
+
+        Ok("OK".into())
+    }
+
+    fn receive(&mut self) -> Result<String> {
+        let mut byte = [0u8];
+
+        loop {
+            // Skip acknowledgements and interrupt requests.
+            loop {
+                self.stream.read_exact(&mut byte)?;
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut packet = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                packet.push(byte[0]);
+            }
+
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let checksum = std::str::from_utf8(&checksum).map_err(|_| invalid())?;
+            let checksum = u8::from_str_radix(checksum, 16).map_err(|_| invalid())?;
+
+            if packet.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != checksum {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+
+            self.stream.write_all(b"+")?;
+            return String::from_utf8(packet).map_err(|_| invalid());
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let checksum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        let mut ack = [0u8];
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+
+            self.stream.read_exact(&mut ack)?;
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The `g` packet of i386:x86-64: 16 GPRs, rip, eflags and six selectors.
+const GPRS: usize = 18;
+const REGISTERS: usize = GPRS + 6;
+
+fn width(n: usize) -> usize {
+    match n {
+        0..=16 => 8,
+        _ => 4,
+    }
+}
+
+fn gpr(regs: &mut arch::Registers, n: usize) -> &mut u64 {
+    match n {
+        0 => &mut regs.rax,
+        1 => &mut regs.rbx,
+        2 => &mut regs.rcx,
+        3 => &mut regs.rdx,
+        4 => &mut regs.rsi,
+        5 => &mut regs.rdi,
+        6 => &mut regs.rbp,
+        7 => &mut regs.rsp,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        15 => &mut regs.r15,
+        16 => &mut regs.rip,
+        _ => &mut regs.rflags,
+    }
+}
+
+fn register(cpu: &VirtualCpu, n: usize) -> Result<String> {
+    let value = if n < GPRS {
+        *gpr(&mut cpu.registers()?, n)
+    } else {
+        let sregs = cpu.special_registers()?;
+        let segment = match n - GPRS {
+            0 => sregs.cs,
+            1 => sregs.ss,
+            2 => sregs.ds,
+            3 => sregs.es,
+            4 => sregs.fs,
+            _ => sregs.gs,
+        };
+        segment.selector as u64
+    };
+
+    Ok(encode(&value.to_le_bytes()[..width(n)]))
+}
+
+fn invalid() -> Error {
+    ErrorKind::InvalidData.into()
+}
+
+fn encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 == 1 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+fn decode_le(hex: &str) -> Result<u64> {
+    let bytes = decode(hex)?;
+    if bytes.len() > 8 {
+        return Err(invalid());
+    }
+
+    let mut value = [0u8; 8];
+    value[..bytes.len()].copy_from_slice(&bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+fn address_length(args: &str) -> Result<(u64, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = parts.next().ok_or_else(invalid)?;
+    let len = parts.next().ok_or_else(invalid)?;
+
+    Ok((
+        u64::from_str_radix(addr, 16).map_err(|_| invalid())?,
+        usize::from_str_radix(len, 16).map_err(|_| invalid())?,
+    ))
+}
+
+fn breakpoint(args: &str) -> Result<(u8, u64, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let kind = parts.next().ok_or_else(invalid)?;
+    let kind = kind.parse().map_err(|_| invalid())?;
+    let (addr, len) = address_length(parts.next().ok_or_else(invalid)?)?;
+    Ok((kind, addr, len))
+}
@@ -0,0 +1,244 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Msi;
+use crate::arch::IoapicState;
+
+const PINS: usize = 24;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const EOI: u64 = 0x40;
+
+const REG_ID: u32 = 0x00;
+const REG_VER: u32 = 0x01;
+const REG_ARB: u32 = 0x02;
+const REG_REDTBL: u32 = 0x10;
+
+const VERSION: u32 = 0x20;
+
+const VECTOR: u64 = 0xff;
+const DELIVERY_MODE: u64 = 0x7 << 8;
+const DEST_MODE: u64 = 1 << 11;
+const DELIVERY_STATUS: u64 = 1 << 12;
+const REMOTE_IRR: u64 = 1 << 14;
+const TRIGGER_LEVEL: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const DEST_SHIFT: u64 = 56;
+
+const READ_ONLY: u64 = DELIVERY_STATUS | REMOTE_IRR;
+
+/// An IOAPIC that translates pin assertions into MSIs.
+///
+/// Every method that may cause an interrupt to be raised returns the
+/// messages that must be delivered, for example with
+/// `VirtualMachine::signal_msi()`.
+pub struct Ioapic(IoapicState, u32);
+
+impl Default for Ioapic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Takes the level of every pin with a pending request to be high, which
+/// is exact for level triggered pins. Use `with_levels()` to restore edge
+/// triggered pins exactly.
+impl From<IoapicState> for Ioapic {
+    fn from(state: IoapicState) -> Self {
+        Self::with_levels(state, state.irr)
+    }
+}
+
+impl Ioapic {
+    /// The guest physical address at which the IOAPIC is normally mapped.
+    pub const BASE: u64 = 0xfec0_0000;
+
+    /// The size of the MMIO window.
+    pub const SIZE: u64 = 0x1000;
+
+    pub fn new() -> Self {
+        Ioapic(
+            IoapicState {
+                base_address: Self::BASE,
+                redirtbl: [MASKED; PINS],
+                ..Default::default()
+            },
+            0,
+        )
+    }
+
+    /// Restores the IOAPIC from its state and the input pin levels.
+    pub fn with_levels(state: IoapicState, levels: u32) -> Self {
+        Ioapic(state, levels & ((1 << PINS) - 1))
+    }
+
+    pub fn state(&self) -> IoapicState {
+        self.0
+    }
+
+    /// The level of each input pin, one bit per pin.
+    pub fn levels(&self) -> u32 {
+        self.1
+    }
+
+    /// Sets the level of an input pin. Pins that do not exist are ignored,
+    /// as they may come from guest controlled routing.
+    pub fn set_irq(&mut self, pin: usize, level: bool) -> Vec<Msi> {
+        if pin >= PINS {
+            return Vec::new();
+        }
+
+        let mask = 1 << pin;
+        let entry = self.0.redirtbl[pin];
+        let rising = level && self.1 & mask == 0;
+
+        if level {
+            self.1 |= mask;
+        } else {
+            self.1 &= !mask;
+        }
+
+        if entry & TRIGGER_LEVEL != 0 {
+            if level {
+                self.0.irr |= mask;
+            } else {
+                self.0.irr &= !mask;
+            }
+        } else if rising {
+            // Edge triggered pins only fire on a low to high transition.
+            self.0.irr |= mask;
+        }
+
+        self.service()
+    }
+
+    /// Handles an end of interrupt broadcast (`Reason::IoapicEoi`).
+    pub fn end_of_interrupt(&mut self, vector: u8) -> Vec<Msi> {
+        for entry in self.0.redirtbl.iter_mut() {
+            if *entry & VECTOR == vector as u64 && *entry & TRIGGER_LEVEL != 0 {
+                *entry &= !REMOTE_IRR;
+            }
+        }
+
+        self.service()
+    }
+
+    /// Handles a 32-bit MMIO read at `offset` from the base address.
+    pub fn read(&self, offset: u64) -> u32 {
+        match offset {
+            IOREGSEL => self.0.ioregsel,
+            IOWIN => self.read_register(self.0.ioregsel),
+            _ => 0,
+        }
+    }
+
+    /// Handles a 32-bit MMIO write at `offset` from the base address.
+    pub fn write(&mut self, offset: u64, value: u32) -> Vec<Msi> {
+        match offset {
+            IOREGSEL => {
+                self.0.ioregsel = value & 0xff;
+                Vec::new()
+            }
+
+            IOWIN => self.write_register(self.0.ioregsel, value),
+            EOI => self.end_of_interrupt(value as u8),
+            _ => Vec::new(),
+        }
+    }
+
+    fn read_register(&self, reg: u32) -> u32 {
+        match reg {
+            REG_ID | REG_ARB => (self.0.id & 0xf) << 24,
+            REG_VER => VERSION | (PINS as u32 - 1) << 16,
+
+            r if r >= REG_REDTBL && r < REG_REDTBL + PINS as u32 * 2 => {
+                let entry = self.0.redirtbl[(r - REG_REDTBL) as usize / 2];
+
+                if r & 1 == 0 {
+                    entry as u32
+                } else {
+                    (entry >> 32) as u32
+                }
+            }
+
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u32, value: u32) -> Vec<Msi> {
+        match reg {
+            REG_ID => self.0.id = (value >> 24) & 0xf,
+
+            r if r >= REG_REDTBL && r < REG_REDTBL + PINS as u32 * 2 => {
+                let entry = &mut self.0.redirtbl[(r - REG_REDTBL) as usize / 2];
+
+                let value = if r & 1 == 0 {
+                    (*entry & !0xffff_ffff) | value as u64
+                } else {
+                    (*entry & 0xffff_ffff) | (value as u64) << 32
+                };
+
+                *entry = (*entry & READ_ONLY) | (value & !READ_ONLY);
+
+                // Edge triggered entries never wait for an EOI.
+                if *entry & TRIGGER_LEVEL == 0 {
+                    *entry &= !REMOTE_IRR;
+                }
+            }
+
+            _ => return Vec::new(),
+        }
+
+        self.service()
+    }
+
+    fn service(&mut self) -> Vec<Msi> {
+        let mut msis = Vec::new();
+
+        for pin in 0..PINS {
+            let mask = 1 << pin;
+            let entry = &mut self.0.redirtbl[pin];
+
+            if self.0.irr & mask == 0 || *entry & MASKED != 0 {
+                continue;
+            }
+
+            if *entry & TRIGGER_LEVEL != 0 {
+                if *entry & REMOTE_IRR != 0 {
+                    continue;
+                }
+
+                *entry |= REMOTE_IRR;
+            } else {
+                self.0.irr &= !mask;
+            }
+
+            msis.push(Self::message(*entry));
+        }
+
+        msis
+    }
+
+    fn message(entry: u64) -> Msi {
+        let dest = (entry >> DEST_SHIFT) & 0xff;
+        let dest_mode = (entry & DEST_MODE) >> 11;
+        let trigger = (entry & TRIGGER_LEVEL) >> 15;
+
+        Msi {
+            address: 0xfee0_0000 | dest << 12 | dest_mode << 2,
+            data: ((entry & (VECTOR | DELIVERY_MODE)) | trigger << 15 | trigger << 14) as u32,
+        }
+    }
+}
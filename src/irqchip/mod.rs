@@ -0,0 +1,35 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Userspace interrupt controllers for use with a split irqchip.
+//!
+//! When `VirtualMachine::enable_split_irqchip()` is used, the local APIC
+//! remains in the kernel while the IOAPIC and (optionally) the 8259 PIC are
+//! emulated here. Their state is kept in the same `arch` structures that
+//! the in-kernel irqchip uses, so it can be snapshotted and restored.
+
+mod ioapic;
+mod pic;
+mod routing;
+
+pub use ioapic::Ioapic;
+pub use pic::Pic;
+pub use routing::GsiRouting;
+
+/// A message signalled interrupt, ready to be delivered to the local APICs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Msi {
+    pub address: u64,
+    pub data: u32,
+}
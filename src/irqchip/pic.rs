@@ -0,0 +1,307 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::arch::PicState;
+
+const MASTER_ELCR_MASK: u8 = 0xf8;
+const SLAVE_ELCR_MASK: u8 = 0xde;
+const CASCADE: u8 = 2;
+
+fn priority(chip: &PicState, mask: u8) -> u8 {
+    if mask == 0 {
+        return 8;
+    }
+
+    let mut priority = 0;
+    while mask & (1 << ((priority + chip.priority_add) & 7)) == 0 {
+        priority += 1;
+    }
+
+    priority
+}
+
+fn pending(chip: &PicState, master: bool) -> Option<u8> {
+    let irq = priority(chip, chip.irr & !chip.imr);
+    if irq == 8 {
+        return None;
+    }
+
+    let mut mask = chip.isr;
+    if chip.special_mask != 0 {
+        mask &= !chip.imr;
+    }
+    if master && chip.special_fully_nested_mode != 0 {
+        mask &= !(1 << CASCADE);
+    }
+
+    if irq < priority(chip, mask) {
+        Some((irq + chip.priority_add) & 7)
+    } else {
+        None
+    }
+}
+
+fn set_irq(chip: &mut PicState, irq: u8, level: bool) {
+    let mask = 1 << irq;
+
+    if chip.elcr & mask != 0 {
+        if level {
+            chip.irr |= mask;
+            chip.last_irr |= mask;
+        } else {
+            chip.irr &= !mask;
+            chip.last_irr &= !mask;
+        }
+    } else if level {
+        if chip.last_irr & mask == 0 {
+            chip.irr |= mask;
+        }
+        chip.last_irr |= mask;
+    } else {
+        chip.last_irr &= !mask;
+    }
+}
+
+fn acknowledge(chip: &mut PicState, irq: u8) {
+    if chip.auto_eoi != 0 {
+        if chip.rotate_on_auto_eoi != 0 {
+            chip.priority_add = (irq + 1) & 7;
+        }
+    } else {
+        chip.isr |= 1 << irq;
+    }
+
+    if chip.elcr & (1 << irq) == 0 {
+        chip.irr &= !(1 << irq);
+    }
+}
+
+fn reset(chip: &mut PicState) {
+    *chip = PicState {
+        elcr: chip.elcr,
+        elcr_mask: chip.elcr_mask,
+        ..Default::default()
+    };
+}
+
+fn write_command(chip: &mut PicState, value: u8) {
+    if value & 0x10 != 0 {
+        // ICW1
+        reset(chip);
+        chip.init_state = 1;
+        chip.init4 = value & 1;
+    } else if value & 0x08 != 0 {
+        // OCW3
+        if value & 0x04 != 0 {
+            chip.poll = 1;
+        }
+        if value & 0x02 != 0 {
+            chip.read_reg_select = value & 1;
+        }
+        if value & 0x40 != 0 {
+            chip.special_mask = (value >> 5) & 1;
+        }
+    } else {
+        // OCW2
+        match value >> 5 {
+            cmd @ 0 | cmd @ 4 => chip.rotate_on_auto_eoi = cmd >> 2,
+
+            cmd @ 1 | cmd @ 5 => {
+                let priority = priority(chip, chip.isr);
+                if priority != 8 {
+                    let irq = (priority + chip.priority_add) & 7;
+                    chip.isr &= !(1 << irq);
+                    if cmd == 5 {
+                        chip.priority_add = (irq + 1) & 7;
+                    }
+                }
+            }
+
+            3 => chip.isr &= !(1 << (value & 7)),
+            6 => chip.priority_add = (value + 1) & 7,
+
+            7 => {
+                let irq = value & 7;
+                chip.isr &= !(1 << irq);
+                chip.priority_add = (irq + 1) & 7;
+            }
+
+            _ => {}
+        }
+    }
+}
+
+fn write_data(chip: &mut PicState, value: u8) {
+    match chip.init_state {
+        0 => chip.imr = value, // OCW1
+
+        1 => {
+            chip.irq_base = value & 0xf8;
+            chip.init_state = 2;
+        }
+
+        2 => chip.init_state = if chip.init4 != 0 { 3 } else { 0 },
+
+        _ => {
+            chip.special_fully_nested_mode = (value >> 4) & 1;
+            chip.auto_eoi = (value >> 1) & 1;
+            chip.init_state = 0;
+        }
+    }
+}
+
+/// A pair of cascaded 8259 programmable interrupt controllers.
+///
+/// The PIC drives the local APIC's `LINT0` pin. Whenever `Pic::pending()`
+/// is true and the vCPU is ready for interrupt injection, the vector from
+/// `Pic::acknowledge()` should be injected with `VirtualCpu::interrupt()`.
+pub struct Pic {
+    master: PicState,
+    slave: PicState,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<(PicState, PicState)> for Pic {
+    fn from((master, slave): (PicState, PicState)) -> Self {
+        Pic { master, slave }
+    }
+}
+
+impl Pic {
+    /// The I/O ports handled by the PIC.
+    pub const PORTS: [u16; 6] = [0x20, 0x21, 0xa0, 0xa1, 0x4d0, 0x4d1];
+
+    pub fn new() -> Self {
+        Pic {
+            master: PicState {
+                elcr_mask: MASTER_ELCR_MASK,
+                ..Default::default()
+            },
+            slave: PicState {
+                elcr_mask: SLAVE_ELCR_MASK,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns the master and slave state.
+    pub fn state(&self) -> (PicState, PicState) {
+        (self.master, self.slave)
+    }
+
+    /// Sets the level of an ISA interrupt line (0 - 15). Other lines are
+    /// ignored, as they may come from guest controlled routing.
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        match irq {
+            0..=7 => set_irq(&mut self.master, irq, level),
+            8..=15 => set_irq(&mut self.slave, irq - 8, level),
+            _ => return,
+        }
+
+        self.update();
+    }
+
+    /// Returns whether the PIC is asserting its interrupt output.
+    pub fn pending(&self) -> bool {
+        pending(&self.master, true).is_some()
+    }
+
+    /// Acknowledges the highest priority interrupt and returns its vector.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = pending(&self.master, true)?;
+        acknowledge(&mut self.master, irq);
+
+        let vector = if irq == CASCADE {
+            match pending(&self.slave, false) {
+                Some(irq) => {
+                    acknowledge(&mut self.slave, irq);
+                    self.slave.irq_base + irq
+                }
+
+                // Spurious interrupt on the slave.
+                None => self.slave.irq_base + 7,
+            }
+        } else {
+            self.master.irq_base + irq
+        };
+
+        self.update();
+        Some(vector)
+    }
+
+    /// Handles an `in` instruction on one of `Pic::PORTS`.
+    pub fn read(&mut self, port: u16) -> u8 {
+        let value = match port {
+            0x20 | 0xa0 => {
+                let chip = self.chip(port);
+
+                if chip.poll != 0 {
+                    chip.poll = 0;
+
+                    match pending(chip, port == 0x20) {
+                        Some(irq) => {
+                            acknowledge(chip, irq);
+                            irq | 0x80
+                        }
+
+                        None => 0x07,
+                    }
+                } else if chip.read_reg_select != 0 {
+                    chip.isr
+                } else {
+                    chip.irr
+                }
+            }
+
+            0x21 | 0xa1 => self.chip(port).imr,
+            0x4d0 => self.master.elcr,
+            0x4d1 => self.slave.elcr,
+            _ => 0xff,
+        };
+
+        self.update();
+        value
+    }
+
+    /// Handles an `out` instruction on one of `Pic::PORTS`.
+    pub fn write(&mut self, port: u16, value: u8) {
+        match port {
+            0x20 | 0xa0 => write_command(self.chip(port), value),
+            0x21 | 0xa1 => write_data(self.chip(port), value),
+            0x4d0 => self.master.elcr = value & self.master.elcr_mask,
+            0x4d1 => self.slave.elcr = value & self.slave.elcr_mask,
+            _ => {}
+        }
+
+        self.update();
+    }
+
+    fn chip(&mut self, port: u16) -> &mut PicState {
+        if port & 0x80 == 0 {
+            &mut self.master
+        } else {
+            &mut self.slave
+        }
+    }
+
+    fn update(&mut self) {
+        let level = pending(&self.slave, false).is_some();
+        set_irq(&mut self.master, CASCADE, level);
+    }
+}
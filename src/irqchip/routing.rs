@@ -0,0 +1,100 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::arch::IrqchipId;
+
+use std::mem::size_of;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Header {
+    nr: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Entry {
+    gsi: u32,
+    kind: u32,
+    flags: u32,
+    pad: u32,
+    data: [u32; 8],
+}
+
+/// A GSI routing table, applied with `VirtualMachine::set_gsi_routing()`.
+///
+/// The table replaces the whole routing configuration of the VM, including
+/// the default routes to the in-kernel irqchip.
+#[derive(Clone, Default)]
+pub struct GsiRouting(Vec<Entry>);
+
+impl GsiRouting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes `gsi` to an input pin of an in-kernel interrupt controller.
+    pub fn irqchip(mut self, gsi: u32, chip: IrqchipId, pin: u32) -> Self {
+        const KVM_IRQ_ROUTING_IRQCHIP: u32 = 1;
+
+        self.0.push(Entry {
+            gsi,
+            kind: KVM_IRQ_ROUTING_IRQCHIP,
+            flags: 0,
+            pad: 0,
+            data: [chip as u32, pin, 0, 0, 0, 0, 0, 0],
+        });
+        self
+    }
+
+    /// Routes `gsi` to a message signalled interrupt.
+    pub fn msi(mut self, gsi: u32, address: u64, data: u32) -> Self {
+        const KVM_IRQ_ROUTING_MSI: u32 = 2;
+
+        self.0.push(Entry {
+            gsi,
+            kind: KVM_IRQ_ROUTING_MSI,
+            flags: 0,
+            pad: 0,
+            data: [address as u32, (address >> 32) as u32, data, 0, 0, 0, 0, 0],
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Encodes the table as a `struct kvm_irq_routing`.
+    pub(crate) fn encode(&self) -> Vec<u32> {
+        let header = size_of::<Header>() / size_of::<u32>();
+        let entry = size_of::<Entry>() / size_of::<u32>();
+
+        let mut buf = vec![0u32; header + entry * self.0.len()];
+        buf[0] = self.0.len() as u32;
+
+        for (i, e) in self.0.iter().enumerate() {
+            let words = &mut buf[header + entry * i..][..entry];
+            words[..4].copy_from_slice(&[e.gsi, e.kind, e.flags, e.pad]);
+            words[4..].copy_from_slice(&e.data);
+        }
+
+        buf
+    }
+}
@@ -13,6 +13,9 @@
 // limitations under the License.
 
 pub mod arch;
+pub mod device;
+pub mod gdb;
+pub mod irqchip;
 pub mod sev;
 pub mod util;
 
@@ -38,16 +41,35 @@ bitflags! {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoSpace {
+    Pio,
+    Mmio,
+}
+
 pub struct VirtualMachine {
     fd: fd::Fd,
     vcpu_mmap_size: usize,
     multi_addr_space: c_uint,
-    mem: HashMap<u16, Vec<map::Map<()>>>,
+    coalesced_mmio: c_uint,
+    coalesced_pio: bool,
+    mem: HashMap<u16, Vec<Slot>>,
+    encrypted: bool,
+    /// Whether guest memory is private, backed by guest_memfd.
+    private: bool,
+}
+
+struct Slot {
+    addr: u64,
+    map: map::Map<()>,
 }
 
 pub struct VirtualCpu {
     fd: fd::Fd,
     run: map::Map<run::Run>,
+    ring: Option<usize>,
+    coalesced: run::Coalesced,
+    pending: bool,
 }
 
 #[derive(Debug)]
@@ -65,4 +87,17 @@ pub enum Reason<'a> {
         data: &'a [u8],
         read: bool,
     },
+    Debug(arch::DebugExit),
+    InterruptWindow,
+    MsrRead {
+        index: u32,
+        data: &'a mut u64,
+    },
+    MsrWrite {
+        index: u32,
+        value: u64,
+    },
+    IoapicEoi {
+        vector: u8,
+    },
 }
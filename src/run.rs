@@ -85,6 +85,9 @@ pub enum ReasonCode {
     S390Stsi = 25,
     IoapicEoi = 26,
     HyperV = 27,
+    ArmNisv = 28,
+    X86Rdmsr = 29,
+    X86Wrmsr = 30,
 }
 
 #[repr(C)]
@@ -111,6 +114,7 @@ pub union ReasonData {
     pub s390_stsi: ReasonS390Stsi,
     pub eoi: ReasonIoApicEoi,
     pub hyperv: HyperVExit,
+    pub msr: ReasonMsr,
     pub padding: [u8; 256usize],
     _bindgen_union_align: [u64; 32usize],
 }
@@ -293,6 +297,16 @@ pub struct ReasonIoApicEoi {
     pub vector: u8,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ReasonMsr {
+    pub error: u8,
+    pub pad: [u8; 7usize],
+    pub reason: u32,
+    pub index: u32,
+    pub data: u64,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct HyperVExit {
@@ -331,3 +345,19 @@ pub struct HyperVExitHcall {
     pub result: u64,
     pub params: [u64; 2usize],
 }
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CoalescedRing {
+    pub first: u32,
+    pub last: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Coalesced {
+    pub phys_addr: u64,
+    pub len: u32,
+    pub pio: u32,
+    pub data: [u8; 8usize],
+}
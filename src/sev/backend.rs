@@ -0,0 +1,139 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Code, Handle, Result};
+use crate::util::fd::Fd;
+use crate::util::ioctl;
+use crate::VirtualMachine;
+
+use std::os::raw::{c_ulong, c_void};
+use std::os::unix::io::AsRawFd;
+
+/// Executes SEV commands on behalf of a `Launch`.
+pub trait Firmware {
+    /// Executes `code`, as `KVM_MEMORY_ENCRYPT_OP` would.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to the argument structure that KVM defines for
+    /// `code`, and any addresses within it must be valid.
+    unsafe fn cmd(&self, vm: &VirtualMachine, code: Code, data: *mut c_void) -> Result<()>;
+
+    /// Releases the firmware context of a guest whose `Guest` is dropped.
+    ///
+    /// KVM decommissions the guest itself when `vm` is destroyed, which
+    /// follows once the `Guest` has closed its vCPUs, so by default this does
+    /// nothing.
+    fn decommission(&self, _vm: &VirtualMachine, _handle: Handle) {}
+
+    /// Pins guest memory that is about to be encrypted, so that the host
+    /// cannot move or swap it.
+    ///
+    /// By default there is nothing to pin, as for firmware that does not
+    /// really encrypt memory.
+    fn register_region(&self, _vm: &VirtualMachine, _mem: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Unpins guest memory registered with `register_region()`.
+    fn unregister_region(&self, _vm: &VirtualMachine, _mem: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: Firmware + ?Sized> Firmware for &F {
+    unsafe fn cmd(&self, vm: &VirtualMachine, code: Code, data: *mut c_void) -> Result<()> {
+        (**self).cmd(vm, code, data)
+    }
+
+    fn decommission(&self, vm: &VirtualMachine, handle: Handle) {
+        (**self).decommission(vm, handle)
+    }
+
+    fn register_region(&self, vm: &VirtualMachine, mem: &[u8]) -> Result<()> {
+        (**self).register_region(vm, mem)
+    }
+
+    fn unregister_region(&self, vm: &VirtualMachine, mem: &[u8]) -> Result<()> {
+        (**self).unregister_region(vm, mem)
+    }
+}
+
+#[repr(C)]
+struct EncRegion {
+    addr: u64,
+    size: u64,
+}
+
+/// The SEV firmware of this host, reached through KVM and `/dev/sev`.
+pub struct Kernel(Fd);
+
+impl Kernel {
+    pub fn open() -> std::io::Result<Self> {
+        Ok(Kernel(Fd::open("/dev/sev")?))
+    }
+}
+
+impl Firmware for Kernel {
+    unsafe fn cmd(&self, vm: &VirtualMachine, code: Code, data: *mut c_void) -> Result<()> {
+        pub const KVM_MEMORY_ENCRYPT_OP: c_ulong = 3221794490;
+
+        #[repr(C)]
+        struct Command {
+            code: Code,
+            data: u64,
+            error: u32,
+            fd: u32,
+        }
+
+        let mut cmd = Command {
+            error: 0,
+            data: data as u64,
+            fd: self.0.as_raw_fd() as u32,
+            code,
+        };
+
+        match vm.fd.ioctl(KVM_MEMORY_ENCRYPT_OP, &mut cmd) {
+            Ok(_) => Ok(()),
+            _ => Err(cmd.error.into()),
+        }
+    }
+
+    fn register_region(&self, vm: &VirtualMachine, mem: &[u8]) -> Result<()> {
+        let region = EncRegion {
+            addr: mem.as_ptr() as u64,
+            size: mem.len() as u64,
+        };
+
+        unsafe {
+            vm.fd.ioctl(ioctl::KVM_MEMORY_ENCRYPT_REG_REGION, &region)?;
+        }
+
+        Ok(())
+    }
+
+    fn unregister_region(&self, vm: &VirtualMachine, mem: &[u8]) -> Result<()> {
+        let region = EncRegion {
+            addr: mem.as_ptr() as u64,
+            size: mem.len() as u64,
+        };
+
+        unsafe {
+            vm.fd
+                .ioctl(ioctl::KVM_MEMORY_ENCRYPT_UNREG_REGION, &region)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Vmsa;
+use ::sev::{firmware::Build, launch};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::Sha256;
+use openssl::sign::Signer;
+
+use std::io::Result;
+use std::mem::size_of_val;
+use std::slice::from_raw_parts;
+
+/// The policy as the firmware sees it.
+pub(super) fn policy_bytes(policy: &launch::Policy) -> &[u8] {
+    unsafe { from_raw_parts(policy as *const _ as *const u8, size_of_val(policy)) }
+}
+
+/// Predicts an SEV launch measurement without launching a guest.
+///
+/// Feed it everything that will be measured, in the order the launch will
+/// measure it: the exact buffers passed to `update_data()` and, for SEV-ES,
+/// one `Vmsa` per vCPU.
+#[derive(Clone)]
+pub struct Digest(Sha256);
+
+impl Default for Digest {
+    fn default() -> Self {
+        Digest(Sha256::new())
+    }
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update_data(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn update_vmsa(&mut self, vmsa: &Vmsa) {
+        self.0.update(vmsa.as_bytes());
+    }
+
+    /// The launch digest: the firmware's hash of all measured data.
+    pub fn digest(&self) -> [u8; 32] {
+        self.0.clone().finish()
+    }
+
+    /// The measurement the firmware will report, given the guest owner's
+    /// transport integrity key and the firmware's nonce.
+    pub fn measurement(
+        &self,
+        build: Build,
+        policy: launch::Policy,
+        tik: &[u8],
+        mnonce: [u8; 16],
+    ) -> Result<launch::Measurement> {
+        let key = PKey::hmac(tik)?;
+        let mut sig = Signer::new(MessageDigest::sha256(), &key)?;
+        sig.update(&[0x04])?;
+        sig.update(&[build.version.0, build.version.1, build.build])?;
+        sig.update(policy_bytes(&policy))?;
+        sig.update(&self.digest())?;
+        sig.update(&mnonce)?;
+
+        let mut measure = [0u8; 32];
+        sig.sign(&mut measure)?;
+
+        Ok(launch::Measurement { measure, mnonce })
+    }
+}
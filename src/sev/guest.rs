@@ -0,0 +1,224 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{cmd, pages, protect, Code, DebugData, Firmware, Handle, Kernel, Result, StatusData};
+use crate::util::map::Map;
+use crate::{MemoryFlags, VirtualCpu, VirtualMachine};
+use ::sev::launch;
+
+use std::io::{Error, ErrorKind};
+use std::mem::zeroed;
+
+/// The lifecycle state of a guest, as tracked by the firmware.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuestState {
+    Uninitialized = 0,
+    LaunchUpdate,
+    LaunchSecret,
+    Running,
+    SendUpdate,
+    ReceiveUpdate,
+    Sent,
+}
+
+impl GuestState {
+    fn from_raw(state: u32) -> Option<Self> {
+        Some(match state {
+            0 => GuestState::Uninitialized,
+            1 => GuestState::LaunchUpdate,
+            2 => GuestState::LaunchSecret,
+            3 => GuestState::Running,
+            4 => GuestState::SendUpdate,
+            5 => GuestState::ReceiveUpdate,
+            6 => GuestState::Sent,
+            _ => return None,
+        })
+    }
+}
+
+/// The firmware's view of a guest.
+///
+/// KVM does not pass on the ASID that the firmware reports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GuestStatus {
+    pub handle: Handle,
+    pub policy: launch::Policy,
+    pub state: GuestState,
+}
+
+/// An encrypted guest, launched or received, its virtual machine and vCPUs.
+///
+/// Dropping it closes the vCPUs, unpins guest memory and decommissions the
+/// guest before its virtual machine is destroyed. vCPUs created other than
+/// through `create_cpu()` keep the virtual machine, and so the guest, alive.
+pub struct Guest<F: Firmware = Kernel> {
+    handle: Handle,
+    fw: F,
+    vm: VirtualMachine,
+    cpus: Vec<VirtualCpu>,
+}
+
+impl<F: Firmware> Guest<F> {
+    pub(super) fn new(handle: Handle, fw: F, vm: VirtualMachine, cpus: Vec<VirtualCpu>) -> Self {
+        Guest {
+            handle,
+            fw,
+            vm,
+            cpus,
+        }
+    }
+
+    pub(super) fn cmd<U>(&self, code: Code, data: U) -> Result<U> {
+        cmd(&self.fw, &self.vm, code, data)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    /// Creates a vCPU, owned by the guest.
+    pub fn create_cpu(&mut self) -> Result<&mut VirtualCpu> {
+        self.cpus.push(VirtualCpu::new(&self.vm)?);
+        Ok(self.cpus.last_mut().unwrap())
+    }
+
+    /// The vCPUs, in the order they were created, including those of the
+    /// launch.
+    pub fn cpus_mut(&mut self) -> &mut [VirtualCpu] {
+        &mut self.cpus
+    }
+
+    /// Adds a pinned region of guest memory, which may not be backed by a
+    /// file that could be truncated.
+    ///
+    /// Unlike the regions of the launch, its contents are not measured.
+    pub fn add_region<T: 'static + Copy>(
+        &mut self,
+        space: u16,
+        flags: MemoryFlags,
+        addr: u64,
+        map: Map<T>,
+    ) -> Result<u16> {
+        if map.is_truncatable() {
+            return Err(Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        let slot = self.vm.insert_region(space, flags, addr, map)?;
+        if let Err(e) = protect(&self.fw, &self.vm, &self.vm.mem[&space][slot as usize]) {
+            self.vm.remove_last_region(space)?;
+            return Err(e);
+        }
+
+        Ok(slot)
+    }
+
+    pub fn status(&self) -> Result<GuestStatus> {
+        let data = StatusData {
+            handle: self.handle.0,
+            policy: unsafe { zeroed() },
+            state: 0,
+        };
+
+        let data = self.cmd(Code::GuestStatus, data)?;
+        let state =
+            GuestState::from_raw(data.state).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+
+        Ok(GuestStatus {
+            handle: Handle(data.handle),
+            policy: data.policy,
+            state,
+        })
+    }
+
+    fn debuggable(&self) -> Result<()> {
+        let status = self.status()?;
+        if status.policy.flags.contains(launch::PolicyFlags::NO_DEBUG) {
+            return Err(Error::from(ErrorKind::PermissionDenied).into());
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts guest memory from the regions of address space 0.
+    ///
+    /// The guest's policy must allow debugging.
+    pub fn read_encrypted(&self, mut gpa: u64, mut buf: &mut [u8]) -> Result<()> {
+        self.debuggable()?;
+
+        while !buf.is_empty() {
+            let (slot, offset) = self.vm.slot(gpa)?;
+            let src = &self.vm.mem[&0][slot].map[offset..];
+            let len = src.len().min(buf.len());
+
+            let data = DebugData {
+                src_addr: src.as_ptr() as u64,
+                dst_addr: buf.as_mut_ptr() as u64,
+                len: len as u32,
+            };
+
+            cmd(&self.fw, &self.vm, Code::DebugDecrypt, data)?;
+            buf = &mut buf[len..];
+            gpa += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts data into guest memory in the regions of address space 0.
+    ///
+    /// The guest's policy must allow debugging.
+    pub fn write_encrypted(&mut self, mut gpa: u64, mut data: &[u8]) -> Result<()> {
+        self.debuggable()?;
+
+        while !data.is_empty() {
+            let (slot, offset) = self.vm.slot(gpa)?;
+            let dst = &mut self.vm.mem.get_mut(&0).unwrap()[slot].map[offset..];
+            let len = dst.len().min(data.len());
+
+            let dbg = DebugData {
+                src_addr: data.as_ptr() as u64,
+                dst_addr: dst.as_mut_ptr() as u64,
+                len: len as u32,
+            };
+
+            cmd(&self.fw, &self.vm, Code::DebugEncrypt, dbg)?;
+            data = &data[len..];
+            gpa += len as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl<F: Firmware> Drop for Guest<F> {
+    fn drop(&mut self) {
+        // KVM only destroys the virtual machine once its vCPUs are closed.
+        self.cpus.clear();
+
+        for slot in self.vm.mem.values().flatten() {
+            let _ = self.fw.unregister_region(&self.vm, pages(slot));
+        }
+
+        self.fw.decommission(&self.vm, self.handle);
+    }
+}
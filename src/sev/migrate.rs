@@ -0,0 +1,287 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Migration of SEV guests between platforms.
+//!
+//! The sender streams the guest's memory of address space 0, re-encrypted
+//! for the target platform, to a writer. The receiver reads that stream into
+//! a new virtual machine with the same memory layout.
+
+use super::{
+    cmd, Code, Firmware, Guest, Handle, Initialized, Kernel, Launch, PacketData, Result, StartData,
+};
+use crate::VirtualMachine;
+use ::sev::{certs, launch};
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::mem::{size_of, size_of_val, zeroed};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+const PAGE_SIZE: u64 = 4096;
+
+const END: u8 = 0;
+const PAGE: u8 = 1;
+
+fn bytes<T>(value: &T) -> &[u8] {
+    unsafe { from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe { from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// The host address of `len` bytes of guest memory at `gpa`.
+fn host(vm: &VirtualMachine, gpa: u64, len: usize) -> std::io::Result<u64> {
+    let (slot, offset) = vm.slot(gpa)?;
+    let mem = &vm.mem[&0][slot].map[offset..];
+
+    if mem.len() < len {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+
+    Ok(mem.as_ptr() as u64)
+}
+
+/// The certificates of a migration target, as exported by its firmware.
+pub struct Target<'a> {
+    pub pdh: &'a certs::sev::Certificate,
+    /// The platform certificates: PEK, OCA and CEK.
+    pub plat_certs: &'a [u8],
+    /// The AMD certificates: ASK and ARK.
+    pub amd_certs: &'a [u8],
+}
+
+pub struct Unsent;
+pub struct Sending;
+pub struct Receiving(Handle);
+
+pub struct Send<T, F: Firmware = Kernel> {
+    state: PhantomData<T>,
+    guest: Guest<F>,
+}
+
+impl<F: Firmware> Send<Unsent, F> {
+    /// Prepares to migrate a launched or received guest.
+    pub fn new(guest: Guest<F>) -> Self {
+        Send {
+            state: PhantomData,
+            guest,
+        }
+    }
+
+    /// Starts the migration to `target`.
+    ///
+    /// `origin` is the PDH of this platform; the receiver needs it to derive
+    /// the transport keys.
+    pub fn start(
+        self,
+        origin: &certs::sev::Certificate,
+        target: Target,
+        writer: &mut impl Write,
+    ) -> Result<Send<Sending, F>> {
+        #[repr(C)]
+        struct Data {
+            policy: launch::Policy,
+            pdh_addr: u64,
+            pdh_size: u32,
+            plat_addr: u64,
+            plat_size: u32,
+            amd_addr: u64,
+            amd_size: u32,
+            session_addr: u64,
+            session_size: u32,
+        }
+
+        let mut session: launch::Session = unsafe { zeroed() };
+        let data = Data {
+            policy: unsafe { zeroed() },
+            pdh_addr: target.pdh as *const _ as u64,
+            pdh_size: size_of_val(target.pdh) as u32,
+            plat_addr: target.plat_certs.as_ptr() as u64,
+            plat_size: target.plat_certs.len() as u32,
+            amd_addr: target.amd_certs.as_ptr() as u64,
+            amd_size: target.amd_certs.len() as u32,
+            session_addr: &mut session as *mut _ as u64,
+            session_size: size_of_val(&session) as u32,
+        };
+
+        let data = self.guest.cmd(Code::SendStart, data)?;
+
+        writer.write_all(bytes(&data.policy))?;
+        writer.write_all(bytes(origin))?;
+        writer.write_all(bytes(&session))?;
+
+        Ok(Send {
+            state: PhantomData,
+            guest: self.guest,
+        })
+    }
+}
+
+impl<F: Firmware> Send<Sending, F> {
+    /// Encrypts and writes every page of guest memory, in address order.
+    pub fn update_all(&mut self, writer: &mut impl Write) -> Result<()> {
+        let mut slots: Vec<_> = self.guest.vm().mem.get(&0).into_iter().flatten().collect();
+        slots.sort_by_key(|slot| slot.addr);
+
+        for slot in slots {
+            let mem = &slot.map[..];
+            let mut offset = 0;
+
+            while offset < mem.len() {
+                let gpa = slot.addr + offset as u64;
+                let len = (PAGE_SIZE - gpa % PAGE_SIZE).min((mem.len() - offset) as u64) as usize;
+
+                let mut header: launch::Header = unsafe { zeroed() };
+                let mut trans = vec![0u8; len];
+                let data = PacketData {
+                    headr_addr: &mut header as *mut _ as u64,
+                    headr_size: size_of_val(&header) as u32,
+                    guest_addr: mem[offset..].as_ptr() as u64,
+                    guest_size: len as u32,
+                    trans_addr: trans.as_mut_ptr() as u64,
+                    trans_size: len as u32,
+                };
+
+                self.guest.cmd(Code::SendUpdateData, data)?;
+
+                writer.write_all(&[PAGE])?;
+                writer.write_all(&gpa.to_le_bytes())?;
+                writer.write_all(&(len as u32).to_le_bytes())?;
+                writer.write_all(bytes(&header))?;
+                writer.write_all(&trans)?;
+
+                offset += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Completes the migration, decommissioning the guest here.
+    pub fn finish(self, writer: &mut impl Write) -> Result<()> {
+        self.guest.cmd(Code::SendFinish, ())?;
+        writer.write_all(&[END])?;
+        Ok(())
+    }
+
+    /// Abandons the migration, leaving the guest able to run here.
+    pub fn cancel(self) -> Result<Guest<F>> {
+        self.guest.cmd(Code::SendCancel, ())?;
+        Ok(self.guest)
+    }
+}
+
+pub struct Receive<T, F: Firmware = Kernel> {
+    state: T,
+    fw: F,
+    vm: VirtualMachine,
+}
+
+impl Receive<Initialized> {
+    /// Prepares `vm` to receive a guest. It must have the same memory layout
+    /// as the sender.
+    pub fn new(vm: VirtualMachine) -> Result<Self> {
+        Self::with_firmware(vm, Kernel::open()?)
+    }
+}
+
+impl<F: Firmware> Receive<Initialized, F> {
+    pub fn with_firmware(vm: VirtualMachine, fw: F) -> Result<Self> {
+        let launch = Launch::<Initialized, F>::with_firmware(vm, fw)?;
+
+        Ok(Receive {
+            state: Initialized,
+            fw: launch.fw,
+            vm: launch.vm,
+        })
+    }
+
+    /// Reads the sender's session and starts receiving.
+    pub fn start(self, reader: &mut impl Read) -> Result<Receive<Receiving, F>> {
+        let mut policy: launch::Policy = unsafe { zeroed() };
+        let mut pdh = vec![0u8; size_of::<certs::sev::Certificate>()];
+        let mut session: launch::Session = unsafe { zeroed() };
+
+        reader.read_exact(bytes_mut(&mut policy))?;
+        reader.read_exact(&mut pdh)?;
+        reader.read_exact(bytes_mut(&mut session))?;
+
+        let data = StartData {
+            handle: 0,
+            policy,
+            dh_addr: pdh.as_ptr() as u64,
+            dh_size: pdh.len() as u32,
+            session_addr: &session as *const _ as u64,
+            session_size: size_of_val(&session) as u32,
+        };
+
+        let handle = cmd(&self.fw, &self.vm, Code::ReceiveStart, data)?.handle;
+
+        Ok(Receive {
+            state: Receiving(Handle(handle)),
+            fw: self.fw,
+            vm: self.vm,
+        })
+    }
+}
+
+impl<F: Firmware> Receive<Receiving, F> {
+    /// Reads and decrypts pages into guest memory until the sender finishes.
+    pub fn update_all(&mut self, reader: &mut impl Read) -> Result<()> {
+        loop {
+            let mut tag = [0u8];
+            reader.read_exact(&mut tag)?;
+
+            match tag[0] {
+                END => return Ok(()),
+                PAGE => (),
+                _ => return Err(Error::from(ErrorKind::InvalidData).into()),
+            }
+
+            let mut gpa = [0u8; 8];
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut gpa)?;
+            reader.read_exact(&mut len)?;
+
+            let gpa = u64::from_le_bytes(gpa);
+            let len = u32::from_le_bytes(len);
+            if len as u64 > PAGE_SIZE - gpa % PAGE_SIZE {
+                return Err(Error::from(ErrorKind::InvalidData).into());
+            }
+
+            let mut header: launch::Header = unsafe { zeroed() };
+            let mut trans = vec![0u8; len as usize];
+            reader.read_exact(bytes_mut(&mut header))?;
+            reader.read_exact(&mut trans)?;
+
+            let data = PacketData {
+                headr_addr: &mut header as *mut _ as u64,
+                headr_size: size_of_val(&header) as u32,
+                guest_addr: host(&self.vm, gpa, len as usize)?,
+                guest_size: len,
+                trans_addr: trans.as_mut_ptr() as u64,
+                trans_size: len,
+            };
+
+            cmd(&self.fw, &self.vm, Code::ReceiveUpdateData, data)?;
+        }
+    }
+
+    pub fn finish(self) -> Result<Guest<F>> {
+        cmd(&self.fw, &self.vm, Code::ReceiveFinish, ())?;
+        Ok(Guest::new(self.state.0, self.fw, self.vm, Vec::new()))
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::mem::size_of_val;
+use std::mem::uninitialized;
+use std::os::raw::c_void;
+use std::slice::from_raw_parts;
+
+use super::*;
+use ::sev::{
+    firmware::{Error, Indeterminate},
+    launch,
+};
+
+/// Re-export of the `sev` crate to mitigate version conflicts in consumers
+pub use ::sev;
+
+mod backend;
+mod digest;
+mod guest;
+mod migrate;
+mod snp;
+mod software;
+mod vmsa;
+
+pub use backend::{Firmware, Kernel};
+pub use digest::Digest;
+pub use guest::{Guest, GuestState, GuestStatus};
+pub use migrate::{Receive, Receiving, Send, Sending, Target, Unsent};
+pub use snp::*;
+pub use software::Software;
+pub use vmsa::{Vmsa, VmsaSegment};
+
+/// The commands of `KVM_MEMORY_ENCRYPT_OP`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Code {
+    Init = 0,
+    EsInit,
+
+    LaunchStart,
+    LaunchUpdateData,
+    LaunchUpdateVmsa,
+    LaunchSecret,
+    LaunchMeasure,
+    LaunchFinish,
+
+    SendStart,
+    SendUpdateData,
+    SendUpdateVmsa,
+    SendFinish,
+
+    ReceiveStart,
+    ReceiveUpdateData,
+    ReceiveUpdateVmsa,
+    ReceiveFinish,
+
+    GuestStatus,
+    DebugDecrypt,
+    DebugEncrypt,
+    CertExport,
+    GetAttestationReport,
+    SendCancel,
+    Init2,
+
+    SnpLaunchStart = 100,
+    SnpLaunchUpdate,
+    SnpLaunchFinish,
+}
+
+type Result<T> = std::result::Result<T, Indeterminate<Error>>;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The firmware's identifier for a guest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Handle(u32);
+
+#[derive(Default)]
+pub struct Initialized;
+/// The regions encrypted so far are tracked by address space and slot.
+pub struct Started(Handle, HashSet<(u16, u16)>);
+pub struct Measured(Handle, launch::Measurement);
+
+#[derive(Default)]
+pub struct EsInitialized;
+pub struct EsStarted(Handle);
+pub struct VmsaUpdated(Handle);
+
+/// A state in which a launch can begin.
+pub trait InitialState: Sized {
+    #[doc(hidden)]
+    fn init<F: Firmware>(launch: &Launch<Self, F>) -> Result<()>;
+}
+
+impl InitialState for Initialized {
+    fn init<F: Firmware>(launch: &Launch<Self, F>) -> Result<()> {
+        launch.cmd(Code::Init, ())?;
+        Ok(())
+    }
+}
+
+impl InitialState for EsInitialized {
+    fn init<F: Firmware>(launch: &Launch<Self, F>) -> Result<()> {
+        launch.cmd(Code::EsInit, ())?;
+        Ok(())
+    }
+}
+
+// Command arguments shared by several commands and the software firmware.
+
+#[repr(C)]
+struct StartData {
+    handle: u32,
+    policy: launch::Policy,
+    dh_addr: u64,
+    dh_size: u32,
+    session_addr: u64,
+    session_size: u32,
+}
+
+#[repr(C)]
+struct BufferData {
+    addr: u64,
+    size: u32,
+}
+
+#[repr(C)]
+struct StatusData {
+    handle: u32,
+    policy: launch::Policy,
+    state: u32,
+}
+
+#[repr(C)]
+struct DebugData {
+    src_addr: u64,
+    dst_addr: u64,
+    len: u32,
+}
+
+#[repr(C)]
+struct PacketData {
+    headr_addr: u64,
+    headr_size: u32,
+    guest_addr: u64,
+    guest_size: u32,
+    trans_addr: u64,
+    trans_size: u32,
+}
+
+pub struct Launch<T, F: Firmware = Kernel> {
+    state: T,
+    fw: F,
+    vm: VirtualMachine,
+    cpus: Vec<VirtualCpu>,
+}
+
+/// The memory of a region, rounded up to whole pages.
+fn pages(slot: &Slot) -> &[u8] {
+    let map = &slot.map[..];
+    let len = (map.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    unsafe { from_raw_parts(map.as_ptr(), len as usize) }
+}
+
+/// Prepares a region to hold encrypted guest memory.
+fn protect<F: Firmware>(fw: &F, vm: &VirtualMachine, slot: &Slot) -> Result<()> {
+    // A truncated file would pull pages out from under the guest.
+    if slot.map.is_truncatable() {
+        return Err(std::io::Error::from(ErrorKind::InvalidInput).into());
+    }
+
+    // Guest memory will hold secrets: keep it out of swap and core dumps.
+    slot.map.lock()?;
+    slot.map.advise(map::Advice::DontDump)?;
+    fw.register_region(vm, pages(slot))
+}
+
+impl<T: InitialState + Default, F: Firmware> Launch<T, F> {
+    /// Begins a launch of `vm` whose commands are executed by `fw`.
+    ///
+    /// Every memory region is pinned, and none may be backed by a file that
+    /// could be truncated; memfds must be sealed against shrinking.
+    pub fn with_firmware(mut vm: VirtualMachine, fw: F) -> Result<Self> {
+        if vm.mem.values().flatten().any(|s| s.map.is_truncatable()) {
+            return Err(std::io::Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        vm.encrypted = true;
+        let l = Launch {
+            state: T::default(),
+            fw,
+            vm,
+            cpus: Vec::new(),
+        };
+        T::init(&l)?;
+
+        for slot in l.vm.mem.values().flatten() {
+            protect(&l.fw, &l.vm, slot)?;
+        }
+
+        Ok(l)
+    }
+}
+
+fn cmd<F: Firmware, U>(fw: &F, vm: &VirtualMachine, code: Code, mut data: U) -> Result<U> {
+    unsafe {
+        fw.cmd(vm, code, &mut data as *mut U as *mut c_void)?;
+    }
+    Ok(data)
+}
+
+impl<T, F: Firmware> Launch<T, F> {
+    fn cmd<U>(&self, code: Code, data: U) -> Result<U> {
+        cmd(&self.fw, &self.vm, code, data)
+    }
+
+    fn launch_start(&self, start: launch::Start) -> Result<Handle> {
+        let data = StartData {
+            handle: 0,
+            policy: start.policy,
+            dh_addr: &start.cert as *const _ as u64,
+            dh_size: size_of_val(&start.cert) as u32,
+            session_addr: &start.session as *const _ as u64,
+            session_size: size_of_val(&start.session) as u32,
+        };
+
+        Ok(Handle(self.cmd(Code::LaunchStart, data)?.handle))
+    }
+
+    fn launch_update_data(&self, data: &[u8]) -> Result<()> {
+        let data = BufferData {
+            addr: data.as_ptr() as u64,
+            size: data.len() as u32,
+        };
+
+        self.cmd(Code::LaunchUpdateData, data)?;
+        Ok(())
+    }
+
+    fn launch_measure(&self) -> Result<launch::Measurement> {
+        let mut measurement: launch::Measurement = unsafe { uninitialized() };
+        let data = BufferData {
+            addr: &mut measurement as *mut _ as u64,
+            size: size_of_val(&measurement) as u32,
+        };
+
+        self.cmd(Code::LaunchMeasure, data)?;
+        Ok(measurement)
+    }
+
+    /// The virtual machine being launched.
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    /// Creates a vCPU, owned by the launch and then by its `Guest`.
+    pub fn create_cpu(&mut self) -> Result<&mut VirtualCpu> {
+        self.cpus.push(VirtualCpu::new(&self.vm)?);
+        Ok(self.cpus.last_mut().unwrap())
+    }
+
+    /// The vCPUs, in the order they were created.
+    pub fn cpus_mut(&mut self) -> &mut [VirtualCpu] {
+        &mut self.cpus
+    }
+}
+
+impl Launch<Initialized> {
+    pub fn new(vm: VirtualMachine) -> Result<Self> {
+        Self::with_firmware(vm, Kernel::open()?)
+    }
+}
+
+impl<F: Firmware> Launch<Initialized, F> {
+    pub fn start(self, start: launch::Start) -> Result<Launch<Started, F>> {
+        let handle = self.launch_start(start)?;
+        Ok(Launch {
+            state: Started(handle, HashSet::new()),
+            fw: self.fw,
+            vm: self.vm,
+            cpus: self.cpus,
+        })
+    }
+}
+
+impl<F: Firmware> Launch<Started, F> {
+    pub fn update_data(&mut self, data: &[u8]) -> Result<()> {
+        self.launch_update_data(data)
+    }
+
+    /// Encrypts and measures a memory region of the virtual machine in place.
+    ///
+    /// The region is measured rounded up to whole pages. A region may only
+    /// be encrypted once.
+    pub fn encrypt_region(&mut self, space: u16, slot: u16) -> Result<()> {
+        let region = match self.vm.mem.get(&space).and_then(|s| s.get(slot as usize)) {
+            Some(region) if !self.state.1.contains(&(space, slot)) => region,
+            _ => return Err(std::io::Error::from(ErrorKind::InvalidInput).into()),
+        };
+
+        self.launch_update_data(pages(region))?;
+        self.state.1.insert((space, slot));
+        Ok(())
+    }
+
+    /// Encrypts every memory region, ordered by address space and then by
+    /// guest physical address, so that the measurement is reproducible.
+    ///
+    /// Nothing is encrypted if any region already was.
+    pub fn encrypt_all(&mut self) -> Result<()> {
+        let mut regions: Vec<_> = self
+            .vm
+            .mem
+            .iter()
+            .flat_map(|(space, slots)| {
+                (0..slots.len() as u16).map(move |i| (*space, slots[i as usize].addr, i))
+            })
+            .collect();
+        regions.sort();
+
+        if regions
+            .iter()
+            .any(|(space, _, slot)| self.state.1.contains(&(*space, *slot)))
+        {
+            return Err(std::io::Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        for (space, _, slot) in regions {
+            self.encrypt_region(space, slot)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn measure(self) -> Result<Launch<Measured, F>> {
+        let measurement = self.launch_measure()?;
+        let Launch {
+            state: Started(handle, _),
+            fw,
+            vm,
+            cpus,
+        } = self;
+
+        Ok(Launch {
+            state: Measured(handle, measurement),
+            fw,
+            vm,
+            cpus,
+        })
+    }
+}
+
+impl Launch<EsInitialized> {
+    /// Begins an SEV-ES launch, in which vCPU register state is encrypted.
+    ///
+    /// vCPUs must be created after this, through `create_cpu()`.
+    pub fn new_es(vm: VirtualMachine) -> Result<Self> {
+        Self::with_firmware(vm, Kernel::open()?)
+    }
+}
+
+impl<F: Firmware> Launch<EsInitialized, F> {
+    /// The policy must require SEV-ES, so that the guest owner's measurement
+    /// covers the encrypted register state.
+    pub fn start(self, start: launch::Start) -> Result<Launch<EsStarted, F>> {
+        if !start
+            .policy
+            .flags
+            .contains(launch::PolicyFlags::ENCRYPTED_STATE)
+        {
+            return Err(std::io::Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        let handle = self.launch_start(start)?;
+        Ok(Launch {
+            state: EsStarted(handle),
+            fw: self.fw,
+            vm: self.vm,
+            cpus: self.cpus,
+        })
+    }
+}
+
+impl<F: Firmware> Launch<EsStarted, F> {
+    pub fn update_data(&mut self, data: &[u8]) -> Result<()> {
+        self.launch_update_data(data)
+    }
+
+    /// Encrypts the current register state of every vCPU into its VMSA.
+    ///
+    /// KVM updates all vCPUs at once, so they must all have been created and
+    /// had their registers set, and all data updates must be done.
+    pub fn update_vmsa(self) -> Result<Launch<VmsaUpdated, F>> {
+        self.cmd(Code::LaunchUpdateVmsa, ())?;
+        let Launch {
+            state: EsStarted(handle),
+            fw,
+            vm,
+            cpus,
+        } = self;
+
+        Ok(Launch {
+            state: VmsaUpdated(handle),
+            fw,
+            vm,
+            cpus,
+        })
+    }
+}
+
+impl<F: Firmware> Launch<VmsaUpdated, F> {
+    pub fn measure(self) -> Result<Launch<Measured, F>> {
+        let measurement = self.launch_measure()?;
+        let Launch {
+            state: VmsaUpdated(handle),
+            fw,
+            vm,
+            cpus,
+        } = self;
+
+        Ok(Launch {
+            state: Measured(handle, measurement),
+            fw,
+            vm,
+            cpus,
+        })
+    }
+}
+
+impl<F: Firmware> Launch<Measured, F> {
+    pub fn measurement(&self) -> launch::Measurement {
+        self.state.1
+    }
+
+    pub fn inject(&self, mut secret: launch::Secret, gaddr: u64, size: u32) -> Result<()> {
+        let data = PacketData {
+            headr_addr: &mut secret.header as *mut _ as u64,
+            headr_size: size_of_val(&secret.header) as u32,
+            guest_addr: gaddr,
+            guest_size: size,
+            trans_addr: secret.ciphertext.as_mut_ptr() as u64,
+            trans_size: secret.ciphertext.len() as u32,
+        };
+
+        self.cmd(Code::LaunchSecret, data)?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Guest<F>> {
+        self.cmd(Code::LaunchFinish, ())?;
+        Ok(Guest::new(self.state.0, self.fw, self.vm, self.cpus))
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The SEV-SNP launch flow.
+//!
+//! SNP guests must be created as SNP VMs, with `VirtualMachine::new_snp()`;
+//! KVM rejects these commands otherwise. Their memory is private to the
+//! guest, held by guest_memfd rather than by the maps of their regions.
+
+use super::{Code, Firmware, Guest, Handle, InitialState, Kernel, Launch, Result};
+use crate::{Kvm, VirtualMachine};
+
+use bitflags::bitflags;
+
+use std::io::{Error, ErrorKind};
+use std::os::raw::c_ulong;
+
+pub const ID_BLOCK_SIZE: usize = 96;
+pub const ID_AUTH_SIZE: usize = 4096;
+
+const PAGE_SIZE: u64 = 4096;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct SnpPolicyFlags: u64 {
+        const SMT = 1 << 16;
+        const MIGRATE_MA = 1 << 18;
+        const DEBUG = 1 << 19;
+        const SINGLE_SOCKET = 1 << 20;
+        const CXL_ALLOW = 1 << 21;
+        const MEM_AES_256_XTS = 1 << 22;
+        const RAPL_DIS = 1 << 23;
+        const CIPHERTEXT_HIDING = 1 << 24;
+        const PAGE_SWAP_DISABLE = 1 << 25;
+    }
+}
+
+/// The guest policy of an SNP launch.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnpPolicy {
+    pub flags: SnpPolicyFlags,
+    /// The minimum firmware ABI version the guest may run on.
+    pub abi_major: u8,
+    pub abi_minor: u8,
+}
+
+impl SnpPolicy {
+    /// The policy as the firmware sees it. Bit 17 is reserved and must be
+    /// set.
+    pub fn bits(&self) -> u64 {
+        self.flags.bits() | 1 << 17 | (self.abi_major as u64) << 8 | self.abi_minor as u64
+    }
+}
+
+/// How the firmware treats pages passed to `Launch<SnpStarted>::update()`.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageType {
+    /// Encrypted and measured.
+    Normal = 1,
+    /// Zeroed and measured; the data is ignored.
+    Zero = 3,
+    /// Encrypted but not measured.
+    Unmeasured = 4,
+    /// Filled by the firmware with the guest's secrets.
+    Secrets = 5,
+    /// A CPUID table, checked by the firmware.
+    Cpuid = 6,
+}
+
+/// A signed identity for the guest, checked by the firmware at finish.
+#[derive(Clone)]
+pub struct IdBlock {
+    pub block: [u8; ID_BLOCK_SIZE],
+    pub auth: Box<[u8; ID_AUTH_SIZE]>,
+    /// Whether `auth` also carries an author key.
+    pub author_key: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct SnpFinish {
+    pub id_block: Option<IdBlock>,
+    /// Data supplied by the host, reflected in attestation reports.
+    pub host_data: [u8; 32],
+}
+
+#[derive(Default)]
+pub struct SnpInitialized;
+pub struct SnpStarted;
+
+impl InitialState for SnpInitialized {
+    fn init<F: Firmware>(launch: &Launch<Self, F>) -> Result<()> {
+        #[repr(C)]
+        #[derive(Default)]
+        struct Data {
+            vmsa_features: u64,
+            flags: u32,
+            ghcb_version: u16,
+            pad1: u16,
+            pad2: [u32; 8],
+        }
+
+        launch.cmd(Code::Init2, Data::default())?;
+        Ok(())
+    }
+}
+
+impl VirtualMachine {
+    /// Creates a virtual machine for an SEV-SNP guest.
+    ///
+    /// Each memory region added to it is also backed by a guest_memfd of the
+    /// same size, which holds the private memory of the guest.
+    pub fn new_snp(kvm: &Kvm) -> std::io::Result<Self> {
+        const KVM_X86_SNP_VM: c_ulong = 4;
+
+        Self::create(kvm, KVM_X86_SNP_VM, true)
+    }
+}
+
+impl Launch<SnpInitialized> {
+    /// Begins an SEV-SNP launch of `vm`, created with
+    /// `VirtualMachine::new_snp()`.
+    ///
+    /// vCPUs must be created after this, through `create_cpu()`.
+    pub fn new_snp(vm: VirtualMachine) -> Result<Self> {
+        if !vm.private {
+            return Err(Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        Self::with_firmware(vm, Kernel::open()?)
+    }
+}
+
+impl<F: Firmware> Launch<SnpInitialized, F> {
+    pub fn start(self, policy: SnpPolicy) -> Result<Launch<SnpStarted, F>> {
+        #[repr(C)]
+        struct Data {
+            policy: u64,
+            gosvw: [u8; 16],
+            flags: u16,
+            pad0: [u8; 6],
+            pad1: [u64; 4],
+        }
+
+        let data = Data {
+            policy: policy.bits(),
+            gosvw: [0; 16],
+            flags: 0,
+            pad0: [0; 6],
+            pad1: [0; 4],
+        };
+
+        self.cmd(Code::SnpLaunchStart, data)?;
+        Ok(Launch {
+            state: SnpStarted,
+            fw: self.fw,
+            vm: self.vm,
+            cpus: self.cpus,
+        })
+    }
+}
+
+impl<F: Firmware> Launch<SnpStarted, F> {
+    /// Places `data` at guest physical address `gpa`.
+    ///
+    /// Both must be page aligned.
+    pub fn update(&mut self, gpa: u64, kind: PageType, data: &[u8]) -> Result<()> {
+        #[repr(C)]
+        struct Data {
+            gfn_start: u64,
+            uaddr: u64,
+            len: u64,
+            kind: PageType,
+            pad0: u8,
+            flags: u16,
+            pad1: u32,
+            pad2: [u64; 4],
+        }
+
+        let len = data.len() as u64;
+        if (gpa | len) & (PAGE_SIZE - 1) != 0 || len == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput).into());
+        }
+
+        let mut data = Data {
+            gfn_start: gpa / PAGE_SIZE,
+            uaddr: data.as_ptr() as u64,
+            len,
+            kind,
+            pad0: 0,
+            flags: 0,
+            pad1: 0,
+            pad2: [0; 4],
+        };
+
+        // KVM may stop early, leaving the remainder described in `data`.
+        while data.len > 0 {
+            data = self.cmd(Code::SnpLaunchUpdate, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Completes the launch. This also encrypts and measures the register
+    /// state of every vCPU, so they must all have been created and had their
+    /// registers set.
+    ///
+    /// KVM does not report the handle of SNP guests, so that of the `Guest`
+    /// is 0.
+    pub fn finish(self, finish: SnpFinish) -> Result<Guest<F>> {
+        #[repr(C)]
+        struct Data {
+            id_block_uaddr: u64,
+            id_auth_uaddr: u64,
+            id_block_en: u8,
+            auth_key_en: u8,
+            vcek_disabled: u8,
+            host_data: [u8; 32],
+            pad0: [u8; 3],
+            flags: u16,
+            pad1: [u64; 4],
+        }
+
+        let id = finish.id_block.as_ref();
+        let data = Data {
+            id_block_uaddr: id.map_or(0, |id| id.block.as_ptr() as u64),
+            id_auth_uaddr: id.map_or(0, |id| id.auth.as_ptr() as u64),
+            id_block_en: id.is_some() as u8,
+            auth_key_en: matches!(id, Some(id) if id.author_key) as u8,
+            vcek_disabled: 0,
+            host_data: finish.host_data,
+            pad0: [0; 3],
+            flags: 0,
+            pad1: [0; 4],
+        };
+
+        self.cmd(Code::SnpLaunchFinish, data)?;
+        Ok(Guest::new(Handle(0), self.fw, self.vm, self.cpus))
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A software stand-in for the SEV firmware, for testing launches on
+//! machines without SEV.
+//!
+//! It speaks the same protocol as the real firmware: it unwraps the guest
+//! owner's transport keys, measures launch data and decrypts injected
+//! secrets. Guest memory is never actually encrypted.
+
+use super::digest::policy_bytes;
+use super::{
+    BufferData, Code, DebugData, Digest, Firmware, GuestState, Handle, PacketData, Result,
+    StartData, StatusData,
+};
+use crate::VirtualMachine;
+use ::sev::{firmware::Build, launch};
+
+use openssl::bn::BigNum;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt, Cipher};
+
+use std::cell::RefCell;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::ptr::{copy, copy_nonoverlapping, read, write};
+use std::slice::from_raw_parts;
+
+// Firmware status codes.
+const INVALID_PLATFORM_STATE: u32 = 0x01;
+const INVALID_GUEST_STATE: u32 = 0x02;
+const INVALID_LENGTH: u32 = 0x04;
+const INVALID_CERTIFICATE: u32 = 0x06;
+const POLICY_FAILURE: u32 = 0x07;
+const BAD_MEASUREMENT: u32 = 0x0b;
+const INVALID_GUEST: u32 = 0x10;
+const INVALID_COMMAND: u32 = 0x11;
+const UNSUPPORTED: u32 = 0x15;
+
+const ECDH_SHA256: u32 = 0x003;
+const ECDH_SHA384: u32 = 0x103;
+const P384: u32 = 2;
+
+const HANDLE: u32 = 1;
+
+#[derive(Copy, Clone)]
+struct Keys {
+    tek: [u8; 16],
+    tik: [u8; 16],
+}
+
+#[derive(Clone)]
+enum State {
+    Uninitialized,
+    Initialized,
+    Started(Keys, launch::Policy, Digest),
+    Measured(Keys, launch::Policy, launch::Measurement),
+    Running(launch::Policy),
+}
+
+impl State {
+    fn guest(&self) -> Option<(launch::Policy, GuestState)> {
+        match *self {
+            State::Started(_, policy, _) => Some((policy, GuestState::LaunchUpdate)),
+            State::Measured(_, policy, _) => Some((policy, GuestState::LaunchSecret)),
+            State::Running(policy) => Some((policy, GuestState::Running)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Software {
+    build: Build,
+    pdh: EcKey<Private>,
+    state: RefCell<State>,
+}
+
+fn group() -> std::io::Result<EcGroup> {
+    Ok(EcGroup::from_curve_name(Nid::SECP384R1)?)
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> std::io::Result<[u8; 32]> {
+    let key = PKey::hmac(key)?;
+    let mut sig = Signer::new(MessageDigest::sha256(), &key)?;
+    for part in parts {
+        sig.update(part)?;
+    }
+
+    let mut mac = [0u8; 32];
+    sig.sign(&mut mac)?;
+    Ok(mac)
+}
+
+/// The SEV key derivation function: NIST SP 800-108 in counter mode, with
+/// HMAC-SHA256.
+fn kdf(key: &[u8], label: &str, context: &[u8]) -> std::io::Result<[u8; 16]> {
+    let bits = (16u32 * 8).to_le_bytes();
+    let mac = hmac(
+        key,
+        &[&1u32.to_le_bytes(), label.as_bytes(), &[0], context, &bits],
+    )?;
+
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&mac[..16]);
+    Ok(out)
+}
+
+fn ctr(key: &[u8], iv: &[u8], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    Ok(decrypt(Cipher::aes_128_ctr(), key, Some(iv), data)?)
+}
+
+/// Reads a little endian integer from a certificate.
+fn number(bytes: &[u8]) -> std::io::Result<BigNum> {
+    let mut be = bytes.to_vec();
+    be.reverse();
+    Ok(BigNum::from_slice(&be)?)
+}
+
+impl Software {
+    pub fn new(build: Build) -> std::io::Result<Self> {
+        let group = group()?;
+
+        Ok(Software {
+            build,
+            pdh: EcKey::generate(&group)?,
+            state: RefCell::new(State::Uninitialized),
+        })
+    }
+
+    /// The platform Diffie-Hellman key that guest owners must use.
+    pub fn pdh(&self) -> std::io::Result<EcKey<Public>> {
+        let group = group()?;
+        Ok(EcKey::from_public_key(&group, self.pdh.public_key())?)
+    }
+
+    /// The shared secret with the owner of an SEV format ECDH certificate.
+    fn agree(&self, cert: &[u8]) -> Result<Vec<u8>> {
+        let u32_at = |offset: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&cert[offset..][..4]);
+            u32::from_le_bytes(buf)
+        };
+
+        if cert.len() < 0xa4 {
+            return Err(INVALID_LENGTH.into());
+        }
+
+        match (u32_at(0x0c), u32_at(0x10)) {
+            (ECDH_SHA256, P384) | (ECDH_SHA384, P384) => (),
+            _ => return Err(INVALID_CERTIFICATE.into()),
+        }
+
+        let x = number(&cert[0x14..][..48])?;
+        let y = number(&cert[0x5c..][..48])?;
+        let group = group()?;
+        let peer = match EcKey::from_public_key_affine_coordinates(&group, &x, &y) {
+            Ok(peer) => peer,
+            Err(_) => return Err(INVALID_CERTIFICATE.into()),
+        };
+
+        let ours = PKey::from_ec_key(self.pdh.clone()).map_err(std::io::Error::from)?;
+        let peer = PKey::from_ec_key(peer).map_err(std::io::Error::from)?;
+
+        let mut deriver = Deriver::new(&ours).map_err(std::io::Error::from)?;
+        deriver.set_peer(&peer).map_err(std::io::Error::from)?;
+        let mut z = deriver.derive_to_vec().map_err(std::io::Error::from)?;
+        z.reverse();
+        Ok(z)
+    }
+
+    unsafe fn start(&self, data: *mut c_void) -> Result<State> {
+        let data = &mut *(data as *mut StartData);
+
+        if data.session_size as usize != size_of::<launch::Session>() {
+            return Err(INVALID_LENGTH.into());
+        }
+
+        let cert = from_raw_parts(data.dh_addr as *const u8, data.dh_size as usize);
+        let session = read(data.session_addr as *const launch::Session);
+
+        let z = self.agree(cert)?;
+        let master = kdf(&z, "sev-master-secret", &session.nonce)?;
+        let kek = kdf(&master, "sev-kek", &[])?;
+        let kik = kdf(&master, "sev-kik", &[])?;
+
+        if !memcmp::eq(&hmac(&kik, &[&session.wrap_tk])?, &session.wrap_mac) {
+            return Err(BAD_MEASUREMENT.into());
+        }
+
+        let tk = ctr(&kek, &session.wrap_iv, &session.wrap_tk)?;
+        let mut keys = Keys {
+            tek: [0; 16],
+            tik: [0; 16],
+        };
+        keys.tek.copy_from_slice(&tk[..16]);
+        keys.tik.copy_from_slice(&tk[16..]);
+
+        let mac = hmac(&keys.tik, &[policy_bytes(&data.policy)])?;
+        if !memcmp::eq(&mac, &session.policy_mac) {
+            return Err(BAD_MEASUREMENT.into());
+        }
+
+        data.handle = HANDLE;
+        Ok(State::Started(keys, data.policy, Digest::new()))
+    }
+
+    unsafe fn secret(
+        &self,
+        keys: Keys,
+        msr: &launch::Measurement,
+        data: *mut c_void,
+    ) -> Result<()> {
+        let data = &*(data as *const PacketData);
+
+        if data.headr_size as usize != size_of::<launch::Header>()
+            || data.guest_size != data.trans_size
+        {
+            return Err(INVALID_LENGTH.into());
+        }
+
+        let header = read(data.headr_addr as *const launch::Header);
+        if header.flags.contains(launch::HeaderFlags::COMPRESSED) {
+            return Err(UNSUPPORTED.into());
+        }
+
+        let ciphertext = from_raw_parts(data.trans_addr as *const u8, data.trans_size as usize);
+        let mac = hmac(
+            &keys.tik,
+            &[
+                &[0x01],
+                &header.flags.bits().to_le_bytes(),
+                &header.iv,
+                &data.guest_size.to_le_bytes(),
+                &data.trans_size.to_le_bytes(),
+                ciphertext,
+                &msr.measure,
+            ],
+        )?;
+
+        if !memcmp::eq(&mac, &header.mac) {
+            return Err(BAD_MEASUREMENT.into());
+        }
+
+        let plaintext = ctr(&keys.tek, &header.iv, ciphertext)?;
+        copy_nonoverlapping(
+            plaintext.as_ptr(),
+            data.guest_addr as *mut u8,
+            plaintext.len(),
+        );
+        Ok(())
+    }
+}
+
+impl Firmware for Software {
+    unsafe fn cmd(&self, _: &VirtualMachine, code: Code, data: *mut c_void) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        // The state only advances if the command succeeds.
+        *state = match (code, state.clone()) {
+            (Code::Init, State::Uninitialized) => State::Initialized,
+            (Code::Init, _) => return Err(INVALID_PLATFORM_STATE.into()),
+
+            (Code::LaunchStart, State::Initialized) => self.start(data)?,
+
+            (Code::LaunchUpdateData, State::Started(keys, policy, mut digest)) => {
+                let data = &*(data as *const BufferData);
+                digest.update_data(from_raw_parts(data.addr as *const u8, data.size as usize));
+                State::Started(keys, policy, digest)
+            }
+
+            (Code::LaunchMeasure, State::Started(keys, policy, digest)) => {
+                let data = &*(data as *const BufferData);
+                if (data.size as usize) < size_of::<launch::Measurement>() {
+                    return Err(INVALID_LENGTH.into());
+                }
+
+                let mut mnonce = [0u8; 16];
+                rand_bytes(&mut mnonce).map_err(std::io::Error::from)?;
+
+                let msr = digest.measurement(self.build, policy, &keys.tik, mnonce)?;
+                write(data.addr as *mut launch::Measurement, msr);
+                State::Measured(keys, policy, msr)
+            }
+
+            (Code::LaunchSecret, State::Measured(keys, _, msr)) => {
+                return self.secret(keys, &msr, data);
+            }
+
+            (Code::LaunchFinish, State::Measured(_, policy, _)) => State::Running(policy),
+
+            (Code::GuestStatus, state) => {
+                let data = &mut *(data as *mut StatusData);
+                let (policy, status) = state.guest().ok_or(INVALID_GUEST)?;
+
+                if data.handle != HANDLE {
+                    return Err(INVALID_GUEST.into());
+                }
+
+                data.policy = policy;
+                data.state = status as u32;
+                return Ok(());
+            }
+
+            // Guest memory is not encrypted, so debugging is a plain copy.
+            (Code::DebugDecrypt, state) | (Code::DebugEncrypt, state) => {
+                let data = &*(data as *const DebugData);
+                let (policy, _) = state.guest().ok_or(INVALID_GUEST)?;
+
+                if policy.flags.contains(launch::PolicyFlags::NO_DEBUG) {
+                    return Err(POLICY_FAILURE.into());
+                }
+
+                copy(
+                    data.src_addr as *const u8,
+                    data.dst_addr as *mut u8,
+                    data.len as usize,
+                );
+                return Ok(());
+            }
+
+            (Code::EsInit, _) | (Code::LaunchUpdateVmsa, _) => return Err(UNSUPPORTED.into()),
+
+            (Code::LaunchStart, _)
+            | (Code::LaunchUpdateData, _)
+            | (Code::LaunchMeasure, _)
+            | (Code::LaunchSecret, _)
+            | (Code::LaunchFinish, _) => return Err(INVALID_GUEST_STATE.into()),
+
+            _ => return Err(INVALID_COMMAND.into()),
+        };
+
+        Ok(())
+    }
+
+    fn decommission(&self, _: &VirtualMachine, handle: Handle) {
+        if handle.0 == HANDLE {
+            self.state.replace(State::Uninitialized);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::arch::{Cr0, Cr4, DescriptorTable, Efer, Registers, Segment, SpecialRegisters};
+
+use std::mem::{size_of, zeroed};
+use std::slice::from_raw_parts;
+
+/// A segment register as laid out in the VMSA.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmsaSegment {
+    pub selector: u16,
+    pub attrib: u16,
+    pub limit: u32,
+    pub base: u64,
+}
+
+impl From<&Segment> for VmsaSegment {
+    fn from(seg: &Segment) -> Self {
+        let present = seg.present != 0 && seg.unusable == 0;
+
+        VmsaSegment {
+            selector: seg.selector,
+            attrib: (seg.kind as u16 & 0xf)
+                | (seg.s as u16 & 1) << 4
+                | (seg.dpl as u16 & 3) << 5
+                | (present as u16) << 7
+                | (seg.avl as u16 & 1) << 8
+                | (seg.l as u16 & 1) << 9
+                | (seg.db as u16 & 1) << 10
+                | (seg.g as u16 & 1) << 11,
+            limit: seg.limit,
+            base: seg.base,
+        }
+    }
+}
+
+impl From<&DescriptorTable> for VmsaSegment {
+    fn from(dt: &DescriptorTable) -> Self {
+        VmsaSegment {
+            selector: 0,
+            attrib: 0,
+            limit: dt.limit as u32,
+            base: dt.base,
+        }
+    }
+}
+
+/// The SEV-ES VM save area: the encrypted vCPU state page.
+///
+/// This is the layout that `Launch::update_vmsa()` encrypts and measures.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct Vmsa {
+    pub es: VmsaSegment,
+    pub cs: VmsaSegment,
+    pub ss: VmsaSegment,
+    pub ds: VmsaSegment,
+    pub fs: VmsaSegment,
+    pub gs: VmsaSegment,
+    pub gdtr: VmsaSegment,
+    pub ldtr: VmsaSegment,
+    pub idtr: VmsaSegment,
+    pub tr: VmsaSegment,
+    pub vmpl0_ssp: u64,
+    pub vmpl1_ssp: u64,
+    pub vmpl2_ssp: u64,
+    pub vmpl3_ssp: u64,
+    pub u_cet: u64,
+    reserved_0c8: [u8; 2],
+    pub vmpl: u8,
+    pub cpl: u8,
+    reserved_0cc: [u8; 4],
+    pub efer: u64,
+    reserved_0d8: [u8; 104],
+    pub xss: u64,
+    pub cr4: u64,
+    pub cr3: u64,
+    pub cr0: u64,
+    pub dr7: u64,
+    pub dr6: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub dr0: u64,
+    pub dr1: u64,
+    pub dr2: u64,
+    pub dr3: u64,
+    pub dr0_addr_mask: u64,
+    pub dr1_addr_mask: u64,
+    pub dr2_addr_mask: u64,
+    pub dr3_addr_mask: u64,
+    reserved_1c0: [u8; 24],
+    pub rsp: u64,
+    pub s_cet: u64,
+    pub ssp: u64,
+    pub isst_addr: u64,
+    pub rax: u64,
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub sfmask: u64,
+    pub kernel_gs_base: u64,
+    pub sysenter_cs: u64,
+    pub sysenter_esp: u64,
+    pub sysenter_eip: u64,
+    pub cr2: u64,
+    reserved_248: [u8; 32],
+    pub g_pat: u64,
+    pub dbgctl: u64,
+    pub br_from: u64,
+    pub br_to: u64,
+    pub last_excp_from: u64,
+    pub last_excp_to: u64,
+    reserved_298: [u8; 80],
+    pub pkru: u32,
+    pub tsc_aux: u32,
+    reserved_2f0: [u8; 24],
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    reserved_320: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    reserved_380: [u8; 16],
+    pub guest_exit_info_1: u64,
+    pub guest_exit_info_2: u64,
+    pub guest_exit_int_info: u64,
+    pub guest_nrip: u64,
+    pub sev_features: u64,
+    pub vintr_ctrl: u64,
+    pub guest_exit_code: u64,
+    pub virtual_tom: u64,
+    pub tlb_id: u64,
+    pub pcpu_id: u64,
+    pub event_inj: u64,
+    pub xcr0: u64,
+    reserved_3f0: [u8; 16],
+    pub x87_dp: u64,
+    pub mxcsr: u32,
+    pub x87_ftw: u16,
+    pub x87_fsw: u16,
+    pub x87_fcw: u16,
+    pub x87_fop: u16,
+    pub x87_ds: u16,
+    pub x87_cs: u16,
+    pub x87_rip: u64,
+    pub fpreg_x87: [u8; 80],
+    pub fpreg_xmm: [u8; 256],
+    pub fpreg_ymm: [u8; 256],
+    reserved_670: [u8; 2448],
+}
+
+impl Default for Vmsa {
+    fn default() -> Self {
+        // Every field is an integer, for which all zeroes is valid.
+        unsafe { zeroed() }
+    }
+}
+
+impl Vmsa {
+    pub const SIZE: usize = 4096;
+
+    /// Builds the VMSA that KVM will encrypt for a vCPU with this state.
+    ///
+    /// KVM's own adjustments are applied: EFER.SVME and CR4.MCE are always
+    /// set, as KVM inherits CR4.MCE from a host that is assumed to enable
+    /// it, and CR0.CD and CR0.NW are cleared. State outside
+    /// of `regs` and `sregs` takes its reset value, with x87, SSE and XSAVE
+    /// in their `fninit` defaults.
+    pub fn new(regs: &Registers, sregs: &SpecialRegisters) -> Self {
+        Vmsa {
+            es: (&sregs.es).into(),
+            cs: (&sregs.cs).into(),
+            ss: (&sregs.ss).into(),
+            ds: (&sregs.ds).into(),
+            fs: (&sregs.fs).into(),
+            gs: (&sregs.gs).into(),
+            gdtr: (&sregs.gdt).into(),
+            ldtr: (&sregs.ldt).into(),
+            idtr: (&sregs.idt).into(),
+            tr: (&sregs.tr).into(),
+            cpl: sregs.ss.dpl,
+            efer: sregs.efer | Efer::SVME.bits(),
+            cr4: sregs.cr4 | Cr4::MCE.bits(),
+            cr3: sregs.cr3,
+            cr0: sregs.cr0 & !(Cr0::CD | Cr0::NW).bits(),
+            cr2: sregs.cr2,
+            dr7: 0x400,
+            dr6: 0xffff_0ff0,
+            g_pat: 0x0007_0406_0007_0406,
+            rflags: regs.rflags,
+            rip: regs.rip,
+            rsp: regs.rsp,
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            xcr0: 1,
+            mxcsr: 0x1f80,
+            x87_fcw: 0x37f,
+            ..Default::default()
+        }
+    }
+
+    /// The page as the firmware sees it.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+    }
+}
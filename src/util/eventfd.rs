@@ -0,0 +1,71 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::fd::Fd;
+
+use std::io::{Error, Result};
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// A counter that can be shared between threads and handed to KVM
+/// (see `VirtualMachine::register_irqfd()` and `register_ioeventfd()`).
+pub struct EventFd(Fd);
+
+impl EventFd {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(EventFd(unsafe { Fd::from_raw_fd(fd) }))
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        let fd = unsafe { libc::fcntl(self.0.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(EventFd(unsafe { Fd::from_raw_fd(fd) }))
+    }
+
+    /// Adds `value` to the counter.
+    pub fn write(&self, value: u64) -> Result<()> {
+        let ptr = &value as *const u64 as *const c_void;
+        if unsafe { libc::write(self.0.as_raw_fd(), ptr, size_of::<u64>()) } < 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the counter is non-zero, then returns and resets it.
+    pub fn read(&self) -> Result<u64> {
+        let mut value = 0u64;
+        let ptr = &mut value as *mut u64 as *mut c_void;
+        if unsafe { libc::read(self.0.as_raw_fd(), ptr, size_of::<u64>()) } < 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(value)
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
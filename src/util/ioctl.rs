@@ -28,6 +28,9 @@ pub const KVM_SET_MEMORY_ALIAS: c_ulong = 1075883587;
 pub const KVM_SET_NR_MMU_PAGES: c_ulong = 44612;
 pub const KVM_GET_NR_MMU_PAGES: c_ulong = 44613;
 
+pub const KVM_REGISTER_COALESCED_MMIO: c_ulong = 1074835047;
+pub const KVM_UNREGISTER_COALESCED_MMIO: c_ulong = 1074835048;
+
 pub const KVM_SET_TSS_ADDR: c_ulong = 44615;
 pub const KVM_SET_IDENTITY_MAP_ADDR: c_ulong = 1074310728;
 pub const KVM_CREATE_IRQCHIP: c_ulong = 44640;
@@ -106,3 +109,7 @@ pub const KVM_MEMORY_ENCRYPT_UNREG_REGION: c_ulong = 2148576956;
 pub const KVM_HYPERV_EVENTFD: c_ulong = 1075359421;
 pub const KVM_GET_NESTED_STATE: c_ulong = 3229658814;
 pub const KVM_SET_NESTED_STATE: c_ulong = 1082175167;
+pub const KVM_X86_SET_MSR_FILTER: c_ulong = 1099476678;
+pub const KVM_SET_USER_MEMORY_REGION2: c_ulong = 1084272201;
+pub const KVM_SET_MEMORY_ATTRIBUTES: c_ulong = 1075883730;
+pub const KVM_CREATE_GUEST_MEMFD: c_ulong = 3225464532;
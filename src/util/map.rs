@@ -28,6 +28,14 @@ pub enum Access {
     Private,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Advice {
+    DontDump,
+    DontFork,
+    WipeOnFork,
+    Mergeable,
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct Protection: c_int {
@@ -53,14 +61,23 @@ pub struct Builder<T: 'static + Copy> {
     flags: Flags,
     fd: RawFd,
     offset: libc::off_t,
+    guard: usize,
 }
 
-pub struct Map<T: 'static + Copy>(*mut T, usize);
+/// A memory mapping with an optional inaccessible guard region on either side.
+pub struct Map<T: 'static + Copy> {
+    ptr: *mut T,
+    len: usize,
+    /// The size of each guard region, in bytes.
+    guard: usize,
+    truncatable: bool,
+}
 
 impl<T: 'static + Copy> Drop for Map<T> {
     fn drop(&mut self) {
         unsafe {
-            libc::munmap(self.0 as *mut c_void, self.1);
+            let base = (self.ptr as *mut u8).sub(self.guard);
+            libc::munmap(base as *mut c_void, self.len + self.guard * 2);
         }
     }
 }
@@ -69,37 +86,37 @@ impl<T: 'static + Copy> Deref for Map<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        unsafe { &*self.0 }
+        unsafe { &*self.ptr }
     }
 }
 
 impl<T: 'static + Copy> DerefMut for Map<T> {
     fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.0 }
+        unsafe { &mut *self.ptr }
     }
 }
 
 impl<T: 'static + Copy> AsRef<T> for Map<T> {
     fn as_ref(&self) -> &T {
-        unsafe { &*self.0 }
+        unsafe { &*self.ptr }
     }
 }
 
 impl<T: 'static + Copy> AsMut<T> for Map<T> {
     fn as_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.0 }
+        unsafe { &mut *self.ptr }
     }
 }
 
 impl<T: 'static + Copy> Borrow<T> for Map<T> {
     fn borrow(&self) -> &T {
-        unsafe { &*self.0 }
+        unsafe { &*self.ptr }
     }
 }
 
 impl<T: 'static + Copy> BorrowMut<T> for Map<T> {
     fn borrow_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.0 }
+        unsafe { &mut *self.ptr }
     }
 }
 
@@ -108,7 +125,7 @@ impl<T: 'static + Copy, I: SliceIndex<[u8]>> Index<I> for Map<T> {
 
     #[inline]
     fn index(&self, index: I) -> &Self::Output {
-        let slice = unsafe { from_raw_parts(self.0 as *const u8, self.1) };
+        let slice = unsafe { from_raw_parts(self.ptr as *const u8, self.len) };
         Index::index(&slice[size_of::<T>()..], index)
     }
 }
@@ -116,7 +133,7 @@ impl<T: 'static + Copy, I: SliceIndex<[u8]>> Index<I> for Map<T> {
 impl<T: 'static + Copy, I: SliceIndex<[u8]>> IndexMut<I> for Map<T> {
     #[inline]
     fn index_mut(&mut self, index: I) -> &mut Self::Output {
-        let slice = unsafe { from_raw_parts_mut(self.0 as *mut u8, self.1) };
+        let slice = unsafe { from_raw_parts_mut(self.ptr as *mut u8, self.len) };
         IndexMut::index_mut(&mut slice[size_of::<T>()..], index)
     }
 }
@@ -133,14 +150,67 @@ impl<T: 'static + Copy> Map<T> {
             prot: Protection::default(),
             addr: 0, // NULL
             fd: -1,
+            guard: 0,
         }
     }
 
     pub unsafe fn cast<U: 'static + Copy>(self) -> Map<U> {
-        let map = Map(self.0 as *mut U, self.1);
+        let map = Map {
+            ptr: self.ptr as *mut U,
+            len: self.len,
+            guard: self.guard,
+            truncatable: self.truncatable,
+        };
         std::mem::forget(self);
         map
     }
+
+    /// Whether the map is backed by a file that could be truncated under it.
+    ///
+    /// Files sealed against shrinking, such as sealed memfds, cannot be.
+    pub fn is_truncatable(&self) -> bool {
+        self.truncatable
+    }
+
+    /// Locks the mapping into RAM so that it is never swapped out.
+    pub fn lock(&self) -> Result<()> {
+        if unsafe { libc::mlock(self.ptr as *const c_void, self.len) } != 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(())
+    }
+
+    pub fn unlock(&self) -> Result<()> {
+        if unsafe { libc::munlock(self.ptr as *const c_void, self.len) } != 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(())
+    }
+
+    pub fn advise(&self, advice: Advice) -> Result<()> {
+        let advice = match advice {
+            Advice::DontDump => libc::MADV_DONTDUMP,
+            Advice::DontFork => libc::MADV_DONTFORK,
+            Advice::WipeOnFork => libc::MADV_WIPEONFORK,
+            Advice::Mergeable => libc::MADV_MERGEABLE,
+        };
+
+        if unsafe { libc::madvise(self.ptr as *mut c_void, self.len, advice) } != 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(())
+    }
+
+    pub fn protect(&mut self, prot: Protection) -> Result<()> {
+        if unsafe { libc::mprotect(self.ptr as *mut c_void, self.len, prot.bits()) } != 0 {
+            Err(Error::last_os_error())?
+        }
+
+        Ok(())
+    }
 }
 
 impl<T: 'static + Copy> Builder<T> {
@@ -168,6 +238,13 @@ impl<T: 'static + Copy> Builder<T> {
         self
     }
 
+    /// Surrounds the mapping with `pages` inaccessible pages on each side.
+    #[inline]
+    pub fn guard(mut self, pages: usize) -> Self {
+        self.guard = pages;
+        self
+    }
+
     #[inline]
     pub fn file(mut self, fd: &impl AsRawFd, offset: libc::off_t) -> Self {
         self.fd = fd.as_raw_fd();
@@ -175,6 +252,15 @@ impl<T: 'static + Copy> Builder<T> {
         self
     }
 
+    fn truncatable(&self) -> bool {
+        if self.flags.contains(Flags::ANONYMOUS) {
+            return false;
+        }
+
+        let seals = unsafe { libc::fcntl(self.fd, libc::F_GET_SEALS) };
+        seals < 0 || seals & libc::F_SEAL_SHRINK == 0
+    }
+
     #[inline]
     pub fn done(self) -> Result<Map<T>> {
         let length = size_of::<T>() + self.extra;
@@ -184,21 +270,76 @@ impl<T: 'static + Copy> Builder<T> {
             Access::Shared => libc::MAP_SHARED,
         };
 
+        if self.guard == 0 {
+            let ptr = unsafe {
+                libc::mmap(
+                    self.addr as *mut c_void,
+                    length,
+                    self.prot.bits(),
+                    access | self.flags.bits(),
+                    self.fd,
+                    self.offset,
+                )
+            };
+
+            if ptr == libc::MAP_FAILED {
+                Err(Error::last_os_error())?
+            }
+
+            return Ok(Map {
+                ptr: ptr as *mut T,
+                len: length,
+                guard: 0,
+                truncatable: self.truncatable(),
+            });
+        }
+
+        let guard = self.guard * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        // Reserve the whole range inaccessible, then map over the middle. The
+        // reservation starts a guard below the hint, so the map lands on it.
+        let hint = match self.addr {
+            0 => 0,
+            addr => addr.saturating_sub(guard),
+        };
+
+        let base = unsafe {
+            libc::mmap(
+                hint as *mut c_void,
+                length + guard * 2,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+
+        if base == libc::MAP_FAILED {
+            Err(Error::last_os_error())?
+        }
+
         let ptr = unsafe {
             libc::mmap(
-                self.addr as *mut c_void,
+                (base as *mut u8).add(guard) as *mut c_void,
                 length,
                 self.prot.bits(),
-                access | self.flags.bits(),
+                access | self.flags.bits() | libc::MAP_FIXED,
                 self.fd,
                 self.offset,
             )
         };
 
-        if ptr.is_null() {
-            Err(Error::last_os_error())?
+        if ptr == libc::MAP_FAILED {
+            let err = Error::last_os_error();
+            unsafe { libc::munmap(base, length + guard * 2) };
+            Err(err)?
         }
 
-        Ok(Map(ptr as *mut T, length))
+        Ok(Map {
+            ptr: ptr as *mut T,
+            len: length,
+            guard,
+            truncatable: self.truncatable(),
+        })
     }
 }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod eventfd;
 pub mod fd;
 pub mod ioctl;
 pub mod map;
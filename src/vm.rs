@@ -13,11 +13,16 @@
 // limitations under the License.
 
 use super::*;
+use crate::arch;
+use crate::device;
+use crate::irqchip;
+use crate::util::eventfd::EventFd;
+use crate::util::ioctl;
 use crate::util::map::Map;
 
 use std::io::{ErrorKind, Result};
 use std::os::raw::{c_int, c_uint, c_ulong};
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -29,30 +34,189 @@ struct Region {
     userspace_addr: u64,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Region2 {
+    slot: u32,
+    flags: u32,
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    guest_memfd_offset: u64,
+    guest_memfd: u32,
+    pad1: u32,
+    pad2: [u64; 14],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CreateGuestMemfd {
+    size: u64,
+    flags: u64,
+    reserved: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct MemoryAttributes {
+    address: u64,
+    size: u64,
+    attributes: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct EnableCap {
+    cap: u32,
+    flags: u32,
+    args: [u64; 4],
+    pad: [u8; 64],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct IrqLevel {
+    irq: u32,
+    level: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Msi {
+    address_lo: u32,
+    address_hi: u32,
+    data: u32,
+    flags: u32,
+    devid: u32,
+    pad: [u8; 12],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CoalescedZone {
+    addr: u64,
+    size: u32,
+    pio: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct MsrFilterRange {
+    flags: u32,
+    nmsrs: u32,
+    base: u32,
+    bitmap: *const u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct MsrFilter {
+    flags: u32,
+    ranges: [MsrFilterRange; arch::MsrFilter::MAX_RANGES],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CreateDevice {
+    kind: u32,
+    fd: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct IrqFd {
+    fd: u32,
+    gsi: u32,
+    flags: u32,
+    resamplefd: u32,
+    pad: [u8; 16],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct IoEventFd {
+    datamatch: u64,
+    addr: u64,
+    len: u32,
+    fd: i32,
+    flags: u32,
+    pad: [u8; 36],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+union IrqchipData {
+    dummy: [u8; 512],
+    pic: arch::PicState,
+    ioapic: arch::IoapicState,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Irqchip {
+    chip_id: arch::IrqchipId,
+    pad: u32,
+    chip: IrqchipData,
+}
+
 impl VirtualMachine {
     pub fn new(kvm: &Kvm) -> Result<Self> {
+        Self::create(kvm, 0, false)
+    }
+
+    /// Creates a virtual machine of type `kind`. If `private`, its memory
+    /// regions are backed by guest_memfd and private to the guest.
+    pub(crate) fn create(kvm: &Kvm, kind: c_ulong, private: bool) -> Result<Self> {
+        const KVM_CAP_COALESCED_MMIO: c_int = 15;
+        const KVM_CAP_COALESCED_PIO: c_int = 162;
         const KVM_CAP_MULTI_ADDRESS_SPACE: c_int = 118;
         const KVM_GET_VCPU_MMAP_SIZE: c_ulong = 44548;
         const KVM_CHECK_EXTENSION: c_ulong = 44547;
         const KVM_CREATE_VM: c_ulong = 44545;
 
-        let (fd, limit, size) = unsafe {
-            let fd = kvm.0.ioctl(KVM_CREATE_VM, 0 as c_ulong)?;
+        let (fd, limit, ring, pio, size) = unsafe {
+            let fd = kvm.0.ioctl(KVM_CREATE_VM, kind)?;
             let fd = fd::Fd::from_raw_fd(fd as c_int);
             let lim = fd.ioctl(KVM_CHECK_EXTENSION, KVM_CAP_MULTI_ADDRESS_SPACE)?;
+            let ring = fd.ioctl(KVM_CHECK_EXTENSION, KVM_CAP_COALESCED_MMIO)?;
+            let pio = fd.ioctl(KVM_CHECK_EXTENSION, KVM_CAP_COALESCED_PIO)?;
             let size = kvm.0.ioctl(KVM_GET_VCPU_MMAP_SIZE, ())?;
-            (fd, lim, size as usize)
+            (fd, lim, ring, pio != 0, size as usize)
         };
 
         Ok(Self {
             multi_addr_space: limit,
+            coalesced_mmio: ring,
+            coalesced_pio: pio,
             vcpu_mmap_size: size,
             mem: HashMap::new(),
+            encrypted: false,
+            private,
             fd,
         })
     }
 
+    /// Adds a region of guest memory.
+    ///
+    /// Once an SEV launch has begun, regions must be added through
+    /// `sev::Guest::add_region()` instead, which pins them.
     pub fn add_region<T: 'static + Copy>(
+        &mut self,
+        space: u16,
+        flags: MemoryFlags,
+        addr: u64,
+        map: Map<T>,
+    ) -> Result<u16> {
+        if self.encrypted {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        self.insert_region(space, flags, addr, map)
+    }
+
+    pub(crate) fn insert_region<T: 'static + Copy>(
         &mut self,
         space: u16,
         flags: MemoryFlags,
@@ -76,11 +240,510 @@ impl VirtualMachine {
             userspace_addr: &mut *map as *mut T as u64,
         };
 
+        if self.private {
+            private_region(&self.fd, &region)?;
+        } else {
+            unsafe {
+                self.fd.ioctl(KVM_SET_USER_MEMORY_REGION, &mut region)?;
+            }
+        }
+
+        maps.push(Slot {
+            addr,
+            map: unsafe { map.cast() },
+        });
+        Ok(slot as u16)
+    }
+
+    /// Removes the region most recently added to address space `space`.
+    pub(crate) fn remove_last_region(&mut self, space: u16) -> Result<()> {
+        const KVM_SET_USER_MEMORY_REGION: c_ulong = 1075883590;
+
+        let maps = self.mem.get_mut(&space).ok_or(ErrorKind::InvalidInput)?;
+        let slot = maps.len().checked_sub(1).ok_or(ErrorKind::InvalidInput)?;
+
+        // A region of size zero deletes the slot.
+        let mut region = Region {
+            slot: slot as u32 | ((space as u32) << 16),
+            flags: MemoryFlags::default(),
+            guest_phys_addr: maps[slot].addr,
+            memory_size: 0,
+            userspace_addr: 0,
+        };
+
         unsafe {
             self.fd.ioctl(KVM_SET_USER_MEMORY_REGION, &mut region)?;
         }
 
-        maps.push(unsafe { map.cast() });
-        Ok(slot as u16)
+        maps.pop();
+        Ok(())
+    }
+
+    /// Finds the slot of address space 0 holding `addr` and the offset of
+    /// `addr` within it.
+    pub(crate) fn slot(&self, addr: u64) -> Result<(usize, usize)> {
+        let slots = self.mem.get(&0).map_or(&[][..], |s| &s[..]);
+
+        for (i, slot) in slots.iter().enumerate() {
+            if addr >= slot.addr && addr - slot.addr < slot.map[..].len() as u64 {
+                return Ok((i, (addr - slot.addr) as usize));
+            }
+        }
+
+        Err(ErrorKind::InvalidInput.into())
+    }
+
+    /// Reads guest physical memory from the regions of address space 0.
+    pub fn read_memory(&self, mut addr: u64, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let (slot, offset) = self.slot(addr)?;
+            let src = &self.mem[&0][slot].map[offset..];
+            let len = src.len().min(buf.len());
+
+            buf[..len].copy_from_slice(&src[..len]);
+            buf = &mut buf[len..];
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Writes guest physical memory in the regions of address space 0.
+    pub fn write_memory(&mut self, mut addr: u64, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let (slot, offset) = self.slot(addr)?;
+            let dst = &mut self.mem.get_mut(&0).unwrap()[slot].map[offset..];
+            let len = dst.len().min(data.len());
+
+            dst[..len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+
+    fn enable_cap(&mut self, cap: u32, args: [u64; 4]) -> Result<()> {
+        let cap = EnableCap {
+            cap,
+            flags: 0,
+            args,
+            pad: [0; 64],
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_ENABLE_CAP, &cap)?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the local APICs in the kernel, leaving the IOAPIC and PIC to
+    /// userspace (see `irqchip`). Must be called before creating any vCPU.
+    pub fn enable_split_irqchip(&mut self, pins: u32) -> Result<()> {
+        const KVM_CAP_SPLIT_IRQCHIP: u32 = 121;
+
+        self.enable_cap(KVM_CAP_SPLIT_IRQCHIP, [pins as u64, 0, 0, 0])
+    }
+
+    pub fn create_irqchip(&mut self) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_CREATE_IRQCHIP, ())?;
+        }
+        Ok(())
+    }
+
+    pub fn create_pit2(&mut self, config: arch::PitConfig) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_CREATE_PIT2, &config)?;
+        }
+        Ok(())
+    }
+
+    pub fn irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        let irq = IrqLevel {
+            irq: gsi,
+            level: level as u32,
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_IRQ_LINE, &irq)?;
+        }
+        Ok(())
+    }
+
+    pub fn irqchip(&self, id: arch::IrqchipId) -> Result<arch::Irqchip> {
+        let mut chip = Irqchip {
+            chip_id: id,
+            pad: 0,
+            chip: IrqchipData { dummy: [0; 512] },
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_IRQCHIP, &mut chip)?;
+
+            Ok(match id {
+                arch::IrqchipId::PicMaster => arch::Irqchip::PicMaster(chip.chip.pic),
+                arch::IrqchipId::PicSlave => arch::Irqchip::PicSlave(chip.chip.pic),
+                arch::IrqchipId::Ioapic => arch::Irqchip::Ioapic(chip.chip.ioapic),
+            })
+        }
+    }
+
+    pub fn set_irqchip(&mut self, irqchip: arch::Irqchip) -> Result<()> {
+        let mut chip = Irqchip {
+            chip_id: irqchip.id(),
+            pad: 0,
+            chip: IrqchipData { dummy: [0; 512] },
+        };
+
+        match irqchip {
+            arch::Irqchip::PicMaster(pic) => chip.chip.pic = pic,
+            arch::Irqchip::PicSlave(pic) => chip.chip.pic = pic,
+            arch::Irqchip::Ioapic(ioapic) => chip.chip.ioapic = ioapic,
+        }
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_IRQCHIP, &chip)?;
+        }
+        Ok(())
+    }
+
+    pub fn pit_state(&self) -> Result<arch::PitState> {
+        let mut state = arch::PitState::default();
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_PIT2, &mut state)?;
+        }
+        Ok(state)
+    }
+
+    pub fn set_pit_state(&mut self, state: arch::PitState) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_PIT2, &state)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_gsi_routing(&mut self, routing: &irqchip::GsiRouting) -> Result<()> {
+        let table = routing.encode();
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_GSI_ROUTING, table.as_ptr())?;
+        }
+        Ok(())
+    }
+
+    /// Injects an MSI. Returns `false` if the guest blocked the interrupt.
+    pub fn signal_msi(&self, address: u64, data: u32) -> Result<bool> {
+        let msi = Msi {
+            address_lo: address as u32,
+            address_hi: (address >> 32) as u32,
+            data,
+            flags: 0,
+            devid: 0,
+            pad: [0; 12],
+        };
+
+        Ok(unsafe { self.fd.ioctl(ioctl::KVM_SIGNAL_MSI, &msi)? } > 0)
+    }
+
+    /// Raises `gsi` whenever `eventfd` is written to. With `resample`, the
+    /// GSI is treated as level triggered and `resample` is signalled when
+    /// the guest acknowledges the interrupt.
+    pub fn register_irqfd(
+        &mut self,
+        eventfd: &EventFd,
+        gsi: u32,
+        resample: Option<&EventFd>,
+    ) -> Result<()> {
+        const KVM_IRQFD_FLAG_RESAMPLE: u32 = 1 << 1;
+
+        let irqfd = IrqFd {
+            fd: eventfd.as_raw_fd() as u32,
+            gsi,
+            flags: resample.map_or(0, |_| KVM_IRQFD_FLAG_RESAMPLE),
+            resamplefd: resample.map_or(0, |r| r.as_raw_fd() as u32),
+            pad: [0; 16],
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_IRQFD, &irqfd)?;
+        }
+        Ok(())
+    }
+
+    pub fn unregister_irqfd(&mut self, eventfd: &EventFd, gsi: u32) -> Result<()> {
+        const KVM_IRQFD_FLAG_DEASSIGN: u32 = 1 << 0;
+
+        let irqfd = IrqFd {
+            fd: eventfd.as_raw_fd() as u32,
+            gsi,
+            flags: KVM_IRQFD_FLAG_DEASSIGN,
+            resamplefd: 0,
+            pad: [0; 16],
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_IRQFD, &irqfd)?;
+        }
+        Ok(())
+    }
+
+    /// Signals `eventfd` instead of exiting when the guest writes `len`
+    /// bytes (optionally only the value `datamatch`) to `addr`.
+    pub fn register_ioeventfd(
+        &mut self,
+        eventfd: &EventFd,
+        addr: u64,
+        len: u32,
+        datamatch: Option<u64>,
+        space: IoSpace,
+    ) -> Result<()> {
+        self.ioeventfd(eventfd, addr, len, datamatch, space, false)
+    }
+
+    pub fn unregister_ioeventfd(
+        &mut self,
+        eventfd: &EventFd,
+        addr: u64,
+        len: u32,
+        datamatch: Option<u64>,
+        space: IoSpace,
+    ) -> Result<()> {
+        self.ioeventfd(eventfd, addr, len, datamatch, space, true)
+    }
+
+    fn ioeventfd(
+        &mut self,
+        eventfd: &EventFd,
+        addr: u64,
+        len: u32,
+        datamatch: Option<u64>,
+        space: IoSpace,
+        deassign: bool,
+    ) -> Result<()> {
+        const KVM_IOEVENTFD_FLAG_DATAMATCH: u32 = 1 << 0;
+        const KVM_IOEVENTFD_FLAG_PIO: u32 = 1 << 1;
+        const KVM_IOEVENTFD_FLAG_DEASSIGN: u32 = 1 << 2;
+
+        let mut flags = 0;
+        if datamatch.is_some() {
+            flags |= KVM_IOEVENTFD_FLAG_DATAMATCH;
+        }
+        if space == IoSpace::Pio {
+            flags |= KVM_IOEVENTFD_FLAG_PIO;
+        }
+        if deassign {
+            flags |= KVM_IOEVENTFD_FLAG_DEASSIGN;
+        }
+
+        let ioeventfd = IoEventFd {
+            datamatch: datamatch.unwrap_or(0),
+            addr,
+            len,
+            fd: eventfd.as_raw_fd(),
+            flags,
+            pad: [0; 36],
+        };
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_IOEVENTFD, &ioeventfd)?;
+        }
+        Ok(())
+    }
+
+    pub fn create_device(&mut self, kind: device::Kind) -> Result<device::Device> {
+        let mut dev = CreateDevice {
+            kind: kind as u32,
+            fd: 0,
+            flags: 0,
+        };
+
+        let fd = unsafe {
+            self.fd.ioctl(ioctl::KVM_CREATE_DEVICE, &mut dev)?;
+            fd::Fd::from_raw_fd(dev.fd as c_int)
+        };
+
+        Ok(device::Device { fd, kind })
+    }
+
+    /// Returns the kvmclock state: the clock in nanoseconds, and flags saying
+    /// which of the other fields are valid.
+    pub fn clock(&self) -> Result<arch::ClockData> {
+        let mut clock = arch::ClockData::default();
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_GET_CLOCK, &mut clock)?;
+        }
+        Ok(clock)
+    }
+
+    /// Sets the kvmclock value, e.g. to one saved by `clock()` before the
+    /// guest was paused. The clock resumes where it stopped.
+    pub fn set_clock(&mut self, mut clock: arch::ClockData) -> Result<()> {
+        clock.flags = arch::ClockFlags::empty();
+        self.write_clock(&clock)
+    }
+
+    /// Sets the kvmclock value, advanced by the wall clock time elapsed since
+    /// `clock()` returned it. This is meant for migration, where the guest
+    /// should not notice the time spent moving it.
+    pub fn set_clock_realtime(&mut self, mut clock: arch::ClockData) -> Result<()> {
+        clock.flags &= arch::ClockFlags::REALTIME;
+        self.write_clock(&clock)
+    }
+
+    fn write_clock(&mut self, clock: &arch::ClockData) -> Result<()> {
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_SET_CLOCK, clock)?;
+        }
+        Ok(())
+    }
+
+    /// Buffers guest writes to a write-only range instead of exiting. The
+    /// writes are returned from `VirtualCpu::run()`, in order, before the
+    /// next exit.
+    pub fn register_coalesced(&mut self, space: IoSpace, addr: u64, size: u32) -> Result<()> {
+        self.coalesced(ioctl::KVM_REGISTER_COALESCED_MMIO, space, addr, size)
+    }
+
+    pub fn unregister_coalesced(&mut self, space: IoSpace, addr: u64, size: u32) -> Result<()> {
+        self.coalesced(ioctl::KVM_UNREGISTER_COALESCED_MMIO, space, addr, size)
+    }
+
+    fn coalesced(&mut self, req: c_ulong, space: IoSpace, addr: u64, size: u32) -> Result<()> {
+        if self.coalesced_mmio == 0 || (space == IoSpace::Pio && !self.coalesced_pio) {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let zone = CoalescedZone {
+            addr,
+            size,
+            pio: (space == IoSpace::Pio) as u32,
+        };
+
+        unsafe {
+            self.fd.ioctl(req, &zone)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the selected kinds of MSR access exit to userspace.
+    pub fn enable_user_space_msr(&mut self, reasons: arch::MsrExitReasons) -> Result<()> {
+        const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+
+        self.enable_cap(KVM_CAP_X86_USER_SPACE_MSR, [reasons.bits(), 0, 0, 0])
+    }
+
+    /// Installs an MSR filter. Denied accesses only exit to userspace if
+    /// `MsrExitReasons::FILTER` was enabled; otherwise they raise #GP.
+    pub fn set_msr_filter(&mut self, filter: &arch::MsrFilter) -> Result<()> {
+        const KVM_MSR_FILTER_DEFAULT_DENY: u32 = 1 << 0;
+
+        if filter.ranges.len() > arch::MsrFilter::MAX_RANGES {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        // KVM reads the bitmaps a long at a time.
+        let mut bitmaps = Vec::with_capacity(filter.ranges.len());
+        for range in filter.ranges.iter() {
+            let count = range.count as usize;
+            if range.count > arch::MsrFilter::MAX_MSRS || range.bitmap.len() * 8 < count {
+                return Err(ErrorKind::InvalidInput.into());
+            }
+
+            let mut bitmap = range.bitmap.clone();
+            bitmap.resize(bitmap.len().max(count.div_ceil(64) * 8), 0);
+            bitmaps.push(bitmap);
+        }
+
+        let mut raw = MsrFilter {
+            flags: 0,
+            ranges: [MsrFilterRange {
+                flags: 0,
+                nmsrs: 0,
+                base: 0,
+                bitmap: std::ptr::null(),
+            }; arch::MsrFilter::MAX_RANGES],
+        };
+
+        if filter.default_deny {
+            raw.flags |= KVM_MSR_FILTER_DEFAULT_DENY;
+        }
+
+        for ((raw, range), bitmap) in raw
+            .ranges
+            .iter_mut()
+            .zip(filter.ranges.iter())
+            .zip(bitmaps.iter())
+        {
+            raw.flags = range.access.bits();
+            raw.nmsrs = range.count;
+            raw.base = range.base;
+            raw.bitmap = bitmap.as_ptr();
+        }
+
+        unsafe {
+            self.fd.ioctl(ioctl::KVM_X86_SET_MSR_FILTER, &raw)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds a region whose memory is backed by a new guest_memfd and private to
+/// the guest. `region` gives the shared view of the memory. KVM keeps the
+/// guest_memfd open for as long as the slot exists.
+fn private_region(vm: &fd::Fd, region: &Region) -> Result<()> {
+    const KVM_MEM_GUEST_MEMFD: u32 = 1 << 2;
+    const KVM_MEMORY_ATTRIBUTE_PRIVATE: u64 = 1 << 3;
+
+    let gmem = CreateGuestMemfd {
+        size: region.memory_size,
+        flags: 0,
+        reserved: [0; 6],
+    };
+
+    let gmem = unsafe {
+        let fd = vm.ioctl(ioctl::KVM_CREATE_GUEST_MEMFD, &gmem)?;
+        fd::Fd::from_raw_fd(fd as c_int)
+    };
+
+    let region2 = Region2 {
+        slot: region.slot,
+        flags: region.flags.bits() | KVM_MEM_GUEST_MEMFD,
+        guest_phys_addr: region.guest_phys_addr,
+        memory_size: region.memory_size,
+        userspace_addr: region.userspace_addr,
+        guest_memfd_offset: 0,
+        guest_memfd: gmem.as_raw_fd() as u32,
+        pad1: 0,
+        pad2: [0; 14],
+    };
+
+    let attributes = MemoryAttributes {
+        address: region.guest_phys_addr,
+        size: region.memory_size,
+        attributes: KVM_MEMORY_ATTRIBUTE_PRIVATE,
+        flags: 0,
+    };
+
+    unsafe {
+        vm.ioctl(ioctl::KVM_SET_USER_MEMORY_REGION2, &region2)?;
+
+        if let Err(e) = vm.ioctl(ioctl::KVM_SET_MEMORY_ATTRIBUTES, &attributes) {
+            // A region of size zero deletes the slot.
+            let delete = Region2 {
+                memory_size: 0,
+                ..region2
+            };
+            vm.ioctl(ioctl::KVM_SET_USER_MEMORY_REGION2, &delete)?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+impl arch::PhysicalMemory for VirtualMachine {
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_memory(addr, buf)
     }
 }
@@ -0,0 +1,150 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{Cr0, Cr4, Efer};
+use ketuvim::*;
+
+/// Decodes differently outside of 32-bit code.
+const CODE32: &[u8] = &[
+    0x66, 0xba, 0xf8, 0x03, // mov $0x3f8, %dx
+    0x00, 0xd8, // add %bl, %al
+    0xee, // out %al, (%dx)
+    0xf4, // hlt
+];
+
+/// Decodes differently outside of 64-bit code.
+const CODE64: &[u8] = &[
+    0x66, 0xba, 0xf8, 0x03, // mov $0x3f8, %dx
+    0x48, 0xc1, 0xe8, 0x20, // shr $32, %rax
+    0xee, // out %al, (%dx)
+    0xf4, // hlt
+];
+
+const TABLES: u64 = 0x1000;
+const CODE: u64 = 0x8000;
+
+fn run(long: bool) -> (arch::Registers, Vec<(Option<u8>, arch::SpecialRegisters)>) {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut memory = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x10000)
+        .done()
+        .unwrap();
+
+    let code = if long { CODE64 } else { CODE32 };
+    memory[CODE as usize..][..code.len()].copy_from_slice(code);
+    vm.add_region(0, MemoryFlags::default(), 0, memory).unwrap();
+
+    if long {
+        arch::long_mode(&mut vm, &mut cpu, TABLES).unwrap();
+    } else {
+        arch::protected_mode(&mut vm, &mut cpu, TABLES).unwrap();
+    }
+
+    cpu.set_registers(arch::Registers {
+        rip: CODE,
+        rax: 0x4_0000_0002,
+        rbx: 2,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    // The data written, if any, and the control registers at each exit.
+    let mut exits = Vec::new();
+
+    loop {
+        let exit = match cpu.run().unwrap() {
+            Reason::Halt => None,
+
+            Reason::Io(ReasonIo::Out { port: 0x03f8, data }) => Some(data[0]),
+
+            r => panic!("Unsupported exit reason: {:?}", r),
+        };
+
+        exits.push((exit, cpu.special_registers().unwrap()));
+
+        if exit.is_none() {
+            return (cpu.registers().unwrap(), exits);
+        }
+    }
+}
+
+#[test]
+fn protected_mode() {
+    let (regs, exits) = run(false);
+    assert_eq!(regs.rip, CODE + CODE32.len() as u64);
+    assert_eq!(exits.len(), 2);
+    assert_eq!(exits[0].0, Some(4));
+    assert_eq!(exits[1].0, None);
+
+    for (_, sregs) in exits {
+        assert_eq!(sregs.cr0 & (Cr0::PE | Cr0::PG).bits(), Cr0::PE.bits());
+        assert_eq!(sregs.cr4 & Cr4::PAE.bits(), 0);
+        assert_eq!(sregs.efer & (Efer::LME | Efer::LMA).bits(), 0);
+    }
+}
+
+#[test]
+fn long_mode() {
+    let (regs, exits) = run(true);
+    assert_eq!(regs.rip, CODE + CODE64.len() as u64);
+    assert_eq!(regs.rax, 4);
+    assert_eq!(exits.len(), 2);
+    assert_eq!(exits[0].0, Some(4));
+    assert_eq!(exits[1].0, None);
+
+    for (_, sregs) in exits {
+        assert_eq!(
+            sregs.cr0 & (Cr0::PE | Cr0::PG).bits(),
+            (Cr0::PE | Cr0::PG).bits()
+        );
+        assert_eq!(sregs.cr4 & Cr4::PAE.bits(), Cr4::PAE.bits());
+        assert_eq!(
+            sregs.efer & (Efer::LME | Efer::LMA).bits(),
+            (Efer::LME | Efer::LMA).bits()
+        );
+    }
+}
+
+#[test]
+fn long_mode_tables() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    // Leftovers from a previous boot.
+    let mut memory = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x10000)
+        .done()
+        .unwrap();
+    memory[..].iter_mut().for_each(|b| *b = 0xff);
+    vm.add_region(0, MemoryFlags::default(), 0, memory).unwrap();
+
+    arch::long_mode(&mut vm, &mut cpu, TABLES).unwrap();
+
+    let mut pml4 = [0u8; 0x1000];
+    vm.read_memory(TABLES + 0x1000, &mut pml4).unwrap();
+    assert!(pml4[8..].iter().all(|b| *b == 0));
+
+    let mut pdpt = [0u8; 0x1000];
+    vm.read_memory(TABLES + 0x2000, &mut pdpt).unwrap();
+    assert!(pdpt[4 * 8..].iter().all(|b| *b == 0));
+}
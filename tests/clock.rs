@@ -0,0 +1,136 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::ClockFlags;
+use ketuvim::*;
+
+use std::thread::sleep;
+use std::time::Duration;
+
+const SECOND: u64 = 1_000_000_000;
+const MILLISECOND: u64 = 1_000_000;
+
+/// Points MSR_KVM_SYSTEM_TIME_NEW at 0x3000 and enables kvmclock.
+const CODE: &[u8] = &[
+    0x66, 0xb9, 0x01, 0x4d, 0x56, 0x4b, // mov $0x4b564d01, %ecx
+    0x66, 0xb8, 0x01, 0x30, 0x00, 0x00, // mov $0x3001, %eax
+    0x66, 0x31, 0xd2, // xor %edx, %edx
+    0x0f, 0x30, // wrmsr
+    0xe6, 0x10, // out %al, $0x10
+    0xf4, // hlt
+];
+
+#[test]
+fn clock() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+
+    let mut clock = vm.clock().unwrap();
+    clock.clock += 1000 * SECOND;
+    let target = clock.clock;
+    vm.set_clock(clock).unwrap();
+
+    let clock = vm.clock().unwrap();
+    assert!(clock.clock >= target);
+    assert!(clock.clock < target + SECOND);
+}
+
+#[test]
+fn pause() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+
+    // The time spent paused is not seen by the guest.
+    let saved = vm.clock().unwrap();
+    sleep(Duration::from_millis(200));
+    vm.set_clock(saved).unwrap();
+
+    let clock = vm.clock().unwrap();
+    assert!(clock.clock >= saved.clock);
+    assert!(clock.clock < saved.clock + 100 * MILLISECOND);
+}
+
+#[test]
+fn migrate() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+
+    // Only hosts with a stable TSC report the wall clock time.
+    let saved = vm.clock().unwrap();
+    if !saved.flags.contains(ClockFlags::REALTIME) {
+        return;
+    }
+
+    sleep(Duration::from_millis(200));
+    vm.set_clock_realtime(saved).unwrap();
+
+    let clock = vm.clock().unwrap();
+    assert!(clock.clock >= saved.clock + 200 * MILLISECOND);
+    assert!(clock.clock < saved.clock + SECOND);
+}
+
+#[test]
+fn kvmclock_ctrl() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut mem = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x4000)
+        .done()
+        .unwrap();
+
+    mem[0x1000..][..CODE.len()].copy_from_slice(CODE);
+    vm.add_region(0, MemoryFlags::default(), 0, mem).unwrap();
+
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    // KVM refuses until the guest has enabled kvmclock.
+    assert!(cpu.kvmclock_ctrl().is_err());
+
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x10, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+    cpu.kvmclock_ctrl().unwrap();
+}
+
+#[test]
+fn tsc() {
+    let kvm = Kvm::open().unwrap();
+    let vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let khz = cpu.tsc_khz().unwrap();
+    assert!(khz > 0);
+    cpu.set_tsc_khz(khz).unwrap();
+    assert_eq!(cpu.tsc_khz().unwrap(), khz);
+
+    // Other frequencies need TSC scaling on the host.
+    if cpu.set_tsc_khz(khz / 2).is_ok() {
+        assert_eq!(cpu.tsc_khz().unwrap(), khz / 2);
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::*;
+
+const CODE: &[u8] = &[
+    0xc6, 0x06, 0x00, 0x30, 0x01, // movb $1, 0x3000
+    0xc6, 0x06, 0x00, 0x30, 0x02, // movb $2, 0x3000
+    0xc6, 0x06, 0x01, 0x30, 0x03, // movb $3, 0x3001
+    0xb0, 0x04, // mov $4, %al
+    0xe6, 0x20, // out %al, $0x20
+    0xb0, 0x05, // mov $5, %al
+    0xe6, 0x20, // out %al, $0x20
+    0xe6, 0x10, // out %al, $0x10
+    0xf4, // hlt
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Exit {
+    Mmio(u64, Vec<u8>),
+    Out(u16, Vec<u8>),
+    Halt,
+}
+
+#[test]
+fn coalesced() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut code = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x1000)
+        .done()
+        .unwrap();
+    code[..CODE.len()].copy_from_slice(CODE);
+    vm.add_region(0, MemoryFlags::default(), 0x1000, code)
+        .unwrap();
+
+    vm.register_coalesced(IoSpace::Mmio, 0x3000, 0x1000)
+        .unwrap();
+    let pio = vm.register_coalesced(IoSpace::Pio, 0x20, 1).is_ok();
+
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut exits = Vec::new();
+    let mut rips = Vec::new();
+    loop {
+        let exit = match cpu.run().unwrap() {
+            Reason::Mmio {
+                addr,
+                data,
+                read: false,
+            } => Exit::Mmio(addr, data.to_vec()),
+            Reason::Io(ReasonIo::Out { port, data }) => Exit::Out(port, data.to_vec()),
+            Reason::Halt => Exit::Halt,
+            r => panic!("Unsupported exit reason: {:?}", r),
+        };
+
+        let halt = exit == Exit::Halt;
+        exits.push(exit);
+        rips.push(cpu.registers().unwrap().rip);
+        if halt {
+            break;
+        }
+    }
+
+    // Buffered or not, every write arrives in program order.
+    assert_eq!(
+        exits,
+        vec![
+            Exit::Mmio(0x3000, vec![1]),
+            Exit::Mmio(0x3000, vec![2]),
+            Exit::Mmio(0x3001, vec![3]),
+            Exit::Out(0x20, vec![4]),
+            Exit::Out(0x20, vec![5]),
+            Exit::Out(0x10, vec![5]),
+            Exit::Halt,
+        ]
+    );
+
+    // Buffered writes are handed out, without re-entering the guest, before
+    // the exit that ended the run: `out $0x10`, or the first uncoalesced
+    // `out $0x20` if the host cannot coalesce PIO.
+    let flush = if pio { 0x1019 } else { 0x1013 };
+    assert!(rips[..3].iter().all(|rip| *rip == flush));
+}
@@ -0,0 +1,239 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixtures shared by the SEV tests, and a stand-in for the firmware for the
+//! flows that `sev::Software` does not speak: migration and SNP launches.
+
+#![allow(dead_code)]
+
+use ketuvim::sev::sev::firmware::{Error, Indeterminate};
+use ketuvim::sev::{Code, Firmware, Handle};
+use ketuvim::util::map;
+use ketuvim::{Kvm, MemoryFlags, VirtualMachine};
+
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+/// The policy of every guest sent.
+const POLICY: u32 = 0x5;
+
+/// The handle of every guest launched or received.
+const HANDLE: u32 = 7;
+
+/// Migrated pages are "encrypted" with a XOR of this.
+const KEY: u8 = 0x5a;
+
+// The command arguments that the stand-in reads, as KVM defines them.
+
+#[repr(C)]
+struct BufferData {
+    addr: u64,
+    size: u32,
+}
+
+#[repr(C)]
+struct SendStartData {
+    policy: u32,
+    pdh_addr: u64,
+    pdh_size: u32,
+    plat_addr: u64,
+    plat_size: u32,
+    amd_addr: u64,
+    amd_size: u32,
+    session_addr: u64,
+    session_size: u32,
+}
+
+#[repr(C)]
+struct StartData {
+    handle: u32,
+    policy: u32,
+    pdh_addr: u64,
+    pdh_size: u32,
+    session_addr: u64,
+    session_size: u32,
+}
+
+#[repr(C)]
+struct PacketData {
+    hdr_addr: u64,
+    hdr_size: u32,
+    guest_addr: u64,
+    guest_size: u32,
+    trans_addr: u64,
+    trans_size: u32,
+}
+
+#[repr(C)]
+struct SnpUpdateData {
+    gfn_start: u64,
+    uaddr: u64,
+    len: u64,
+    kind: u8,
+    pad0: u8,
+    flags: u16,
+    pad1: u32,
+    pad2: [u64; 4],
+}
+
+#[repr(C)]
+struct SnpFinishData {
+    id_block_uaddr: u64,
+    id_auth_uaddr: u64,
+    id_block_en: u8,
+    auth_key_en: u8,
+    vcek_disabled: u8,
+    host_data: [u8; 32],
+    pad0: [u8; 3],
+    flags: u16,
+    pad1: [u64; 4],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Command(Code),
+    Decommission(Handle),
+    SnpStart(u64),
+    SnpPage(u64, u8, u8),
+    SnpFinish(bool, [u8; 32]),
+}
+
+/// Anonymous guest memory of `size` bytes.
+pub fn memory(size: usize) -> map::Map<()> {
+    map::Map::<()>::build(map::Access::Shared)
+        .protection(map::Protection::READ | map::Protection::WRITE)
+        .flags(map::Flags::ANONYMOUS)
+        .extra(size)
+        .done()
+        .unwrap()
+}
+
+/// A virtual machine with anonymous memory at each address and size of
+/// `regions`, added in order.
+pub fn vm(regions: &[(u64, usize)]) -> VirtualMachine {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+
+    for &(addr, size) in regions {
+        vm.add_region(0, MemoryFlags::default(), addr, memory(size))
+            .unwrap();
+    }
+
+    vm
+}
+
+/// Records what the firmware is asked to do, one page at a time. SEV launches
+/// are accepted without any checks, to get a guest to migrate.
+#[derive(Clone, Default)]
+pub struct StandIn(Rc<RefCell<Vec<Event>>>);
+
+impl StandIn {
+    pub fn events(&self) -> Vec<Event> {
+        self.0.replace(Vec::new())
+    }
+}
+
+impl Firmware for StandIn {
+    unsafe fn cmd(
+        &self,
+        _: &VirtualMachine,
+        code: Code,
+        data: *mut c_void,
+    ) -> Result<(), Indeterminate<Error>> {
+        let mut log = self.0.borrow_mut();
+
+        match code {
+            Code::Init
+            | Code::Init2
+            | Code::LaunchFinish
+            | Code::SendFinish
+            | Code::SendCancel
+            | Code::ReceiveFinish => log.push(Event::Command(code)),
+
+            Code::LaunchStart => {
+                let data = &mut *(data as *mut StartData);
+                data.handle = HANDLE;
+                log.push(Event::Command(code));
+            }
+
+            Code::LaunchMeasure => {
+                let data = &*(data as *const BufferData);
+                from_raw_parts_mut(data.addr as *mut u8, data.size as usize)
+                    .iter_mut()
+                    .for_each(|b| *b = 0);
+                log.push(Event::Command(code));
+            }
+
+            Code::SendStart => {
+                let data = &mut *(data as *mut SendStartData);
+                data.policy = POLICY;
+                let session =
+                    from_raw_parts_mut(data.session_addr as *mut u8, data.session_size as usize);
+                session.iter_mut().for_each(|b| *b = 0xab);
+                log.push(Event::Command(code));
+            }
+
+            Code::ReceiveStart => {
+                let data = &mut *(data as *mut StartData);
+                let session =
+                    from_raw_parts(data.session_addr as *const u8, data.session_size as usize);
+                assert_eq!(data.policy, POLICY);
+                assert!(session.iter().all(|b| *b == 0xab));
+                data.handle = HANDLE;
+                log.push(Event::Command(code));
+            }
+
+            Code::SendUpdateData | Code::ReceiveUpdateData => {
+                let data = &*(data as *const PacketData);
+                let guest =
+                    from_raw_parts_mut(data.guest_addr as *mut u8, data.guest_size as usize);
+                let trans =
+                    from_raw_parts_mut(data.trans_addr as *mut u8, data.trans_size as usize);
+
+                let (src, dst) = match code {
+                    Code::SendUpdateData => (guest, trans),
+                    _ => (trans, guest),
+                };
+
+                for (d, s) in dst.iter_mut().zip(src.iter()) {
+                    *d = *s ^ KEY;
+                }
+            }
+
+            Code::SnpLaunchStart => log.push(Event::SnpStart(*(data as *const u64))),
+            Code::SnpLaunchUpdate => {
+                let update = &mut *(data as *mut SnpUpdateData);
+                let first = *(update.uaddr as *const u8);
+                log.push(Event::SnpPage(update.gfn_start, update.kind, first));
+
+                update.gfn_start += 1;
+                update.uaddr += 4096;
+                update.len -= 4096;
+            }
+            Code::SnpLaunchFinish => {
+                let finish = &*(data as *const SnpFinishData);
+                log.push(Event::SnpFinish(finish.id_block_en != 0, finish.host_data));
+            }
+            _ => return Err(0xffu32.into()),
+        }
+
+        Ok(())
+    }
+
+    fn decommission(&self, _: &VirtualMachine, handle: Handle) {
+        self.0.borrow_mut().push(Event::Decommission(handle));
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{Breakpoint, BreakpointKind, GuestDebug};
+use ketuvim::*;
+
+const CODE: &[u8] = &[
+    0x90, // nop
+    0x90, // nop
+    0x90, // nop
+    0xe6, 0x10, // out %al, $0x10
+    0xf4, // hlt
+];
+
+/// DR6.B0: breakpoint 0 was hit.
+const DR6_B0: u64 = 1 << 0;
+
+/// DR6.BS: the exit came from single-stepping.
+const DR6_BS: u64 = 1 << 14;
+
+fn debug(reason: Reason) -> arch::DebugExit {
+    match reason {
+        Reason::Debug(exit) => exit,
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+}
+
+#[test]
+fn debug_exits() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut mem = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x2000)
+        .done()
+        .unwrap();
+
+    mem[0x1000..][..CODE.len()].copy_from_slice(CODE);
+    vm.add_region(0, MemoryFlags::default(), 0, mem).unwrap();
+
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    // A single step stops after the first instruction.
+    cpu.set_guest_debug(&GuestDebug {
+        single_step: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let exit = debug(cpu.run().unwrap());
+    assert_eq!(exit.exception, 1);
+    assert_eq!(exit.pc, 0x1001);
+    assert_eq!(exit.dr6 & DR6_BS, DR6_BS);
+
+    // An execute breakpoint stops before the instruction runs.
+    let mut config = GuestDebug::default();
+    config.breakpoints[0] = Some(Breakpoint {
+        addr: 0x1002,
+        kind: BreakpointKind::Execute,
+        len: 1,
+    });
+    cpu.set_guest_debug(&config).unwrap();
+
+    let exit = debug(cpu.run().unwrap());
+    assert_eq!(exit.exception, 1);
+    assert_eq!(exit.pc, 0x1002);
+    assert_eq!(exit.dr6 & (DR6_B0 | DR6_BS), DR6_B0);
+    assert_eq!(cpu.registers().unwrap().rip, 0x1002);
+
+    cpu.set_guest_debug(&GuestDebug::default()).unwrap();
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x10, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+}
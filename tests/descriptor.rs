@@ -0,0 +1,97 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{DescriptorTable, Gate, GateKind, Segment};
+
+#[test]
+fn encode() {
+    assert_eq!(Segment::code64(0x08).descriptor(), 0x00af_9b00_0000_ffff);
+    assert_eq!(Segment::data(0x10).descriptor(), 0x00cf_9300_0000_ffff);
+    assert_eq!(Segment::code32(0x18).descriptor(), 0x00cf_9b00_0000_ffff);
+    assert_eq!(Segment::code64(0x2b).descriptor(), 0x00af_fb00_0000_ffff);
+}
+
+#[test]
+fn roundtrip() {
+    for seg in &[
+        Segment::code64(0x08),
+        Segment::data(0x13),
+        Segment::code32(0x18),
+    ] {
+        assert_eq!(
+            Segment::from_descriptor(seg.selector, seg.descriptor()),
+            *seg
+        );
+    }
+
+    let tss = Segment::tss(0x20, 0xffff_8000_1234_5678, 0x67);
+    let desc = tss.system_descriptor();
+    assert_eq!(desc[1], 0xffff_8000);
+    assert_eq!(Segment::from_system_descriptor(0x20, desc), tss);
+
+    let null = Segment::from_descriptor(0, 0);
+    assert_eq!(null.unusable, 1);
+}
+
+#[test]
+fn gdt() {
+    let tss = Segment::tss(0x18, 0x1_0000_2000, 0x67);
+    let entries = [
+        0,
+        Segment::code64(0x08).descriptor(),
+        Segment::data(0x10).descriptor(),
+        tss.system_descriptor()[0],
+        tss.system_descriptor()[1],
+    ];
+
+    let mut mem = vec![0u8; 0x2000];
+    for (i, e) in entries.iter().enumerate() {
+        mem[0x1000 + i * 8..][..8].copy_from_slice(&e.to_le_bytes());
+    }
+
+    let table = DescriptorTable {
+        base: 0x1000,
+        limit: entries.len() as u16 * 8 - 1,
+        ..Default::default()
+    };
+
+    let code = Segment::from_gdt(&mem[..], &table, 0x08, true).unwrap();
+    assert_eq!(code, Segment::code64(0x08));
+    assert_eq!(
+        Segment::from_gdt(&mem[..], &table, 0x18, true).unwrap(),
+        tss
+    );
+
+    assert!(Segment::from_gdt(&mem[..], &table, 0, true).is_err());
+    assert!(Segment::from_gdt(&mem[..], &table, 0x28, true).is_err());
+    assert!(Segment::from_gdt(&mem[..], &table, 0x0c, true).is_err());
+}
+
+#[test]
+fn gates() {
+    let gate = Gate::interrupt(0x08, 0xffff_ffff_8123_4567);
+    let desc = gate.long_descriptor();
+    assert_eq!(desc[0], 0x8123_8e00_0008_4567);
+    assert_eq!(desc[1], 0xffff_ffff);
+    assert_eq!(Gate::from_long_descriptor(desc), Some(gate));
+
+    let trap = Gate {
+        dpl: 3,
+        ..Gate::trap(0x08, 0x1234_5678)
+    };
+    assert_eq!(trap.kind, GateKind::Trap);
+    assert_eq!(Gate::from_descriptor(trap.descriptor()), Some(trap));
+
+    assert_eq!(Gate::from_descriptor(0x0000_8500_0028_0000), None);
+}
@@ -0,0 +1,29 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::device::{Kind, Raw, Vfio};
+use ketuvim::*;
+
+#[test]
+fn vfio() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let dev = vm.create_device(Kind::Vfio).unwrap();
+
+    assert!(dev.has_attr(&Vfio::GroupAdd).unwrap());
+    assert!(!dev.has_attr(&unsafe { Raw::<u64>::new(0x100, 0) }).unwrap());
+
+    // Not a VFIO group.
+    assert!(dev.set_attr(&Vfio::GroupAdd, &-1).is_err());
+}
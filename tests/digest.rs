@@ -0,0 +1,104 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::sev::sev::{
+    firmware::{Build, Version},
+    launch::{Policy, PolicyFlags},
+};
+use ketuvim::sev::{Digest, Vmsa};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn empty() {
+    assert_eq!(
+        hex(&Digest::new().digest()),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+}
+
+#[test]
+fn measurement() {
+    let mut digest = Digest::new();
+    digest.update_data(b"hello");
+    digest.update_vmsa(&Vmsa::default());
+
+    assert_eq!(
+        hex(&digest.digest()),
+        "d7e8ce15ae42e5663eaabfc13c5d8666dad0ac53525c20f7c477f8abce7bea0b"
+    );
+
+    let build = Build {
+        version: Version(0, 17),
+        build: 5,
+    };
+
+    let policy = Policy {
+        flags: PolicyFlags::NO_DEBUG,
+        minfw: Version(0, 17),
+    };
+
+    let msr = digest
+        .measurement(build, policy, &[1; 16], [2; 16])
+        .unwrap();
+    assert_eq!(msr.mnonce, [2; 16]);
+    assert_eq!(
+        hex(&msr.measure),
+        "fb707d71b99e446b9814457366c70a81a99900cdde28758306be3577f6d77c65"
+    );
+}
+
+/// The expected values were computed with Python's hashlib and hmac, from
+/// the formula of the SEV API specification:
+///
+///   LD = SHA-256(data)
+///   MEASURE = HMAC-SHA-256(TIK, 0x04 || API_MAJOR || API_MINOR || BUILD ||
+///                               POLICY (u32 LE) || LD || MNONCE)
+#[test]
+fn known_answer() {
+    let mut digest = Digest::new();
+    digest.update_data(b"first");
+    digest.update_data(b"second");
+
+    assert_eq!(
+        hex(&digest.digest()),
+        "da83f63e1a473003712c18f5afc5a79044221943d1083c7c5a7ac7236d85e8d2"
+    );
+
+    let build = Build {
+        version: Version(0, 24),
+        build: 15,
+    };
+
+    // 0x0201_0003
+    let policy = Policy {
+        flags: PolicyFlags::NO_DEBUG | PolicyFlags::NO_KEY_SHARING,
+        minfw: Version(1, 2),
+    };
+
+    let mut tik = [0u8; 16];
+    let mut mnonce = [0u8; 16];
+    for i in 0..16 {
+        tik[i] = i as u8;
+        mnonce[i] = 0x10 + i as u8;
+    }
+
+    let msr = digest.measurement(build, policy, &tik, mnonce).unwrap();
+    assert_eq!(
+        hex(&msr.measure),
+        "efcbc183ca0bfdfc51ff2f94c0d5d8b035fafb4dbac3e481f3788f28a88d3408"
+    );
+}
@@ -0,0 +1,113 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{Irqchip, IrqchipId, PicState};
+use ketuvim::util::eventfd::EventFd;
+use ketuvim::*;
+
+use std::thread::sleep;
+use std::time::Duration;
+
+const CODE: &[u8] = &[
+    0xe6, 0x10, // out %al, $0x10
+    0xe6, 0x11, // out %al, $0x11
+    0xe6, 0x10, // out %al, $0x10
+    0xf4, // hlt
+];
+
+fn master(vm: &VirtualMachine) -> PicState {
+    match vm.irqchip(IrqchipId::PicMaster).unwrap() {
+        Irqchip::PicMaster(pic) => pic,
+        chip => panic!("unexpected chip: {:?}", chip),
+    }
+}
+
+#[test]
+fn ioeventfd() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut mem = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x2000)
+        .done()
+        .unwrap();
+
+    mem[0x1000..][..CODE.len()].copy_from_slice(CODE);
+    vm.add_region(0, MemoryFlags::default(), 0, mem).unwrap();
+
+    let eventfd = EventFd::new().unwrap();
+    vm.register_ioeventfd(&eventfd, 0x10, 1, None, IoSpace::Pio)
+        .unwrap();
+
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rsp: 0x2000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    // The first write to port 0x10 only signals the eventfd.
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x11, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+    assert_eq!(eventfd.read().unwrap(), 1);
+
+    // Once unregistered, the write exits again.
+    vm.unregister_ioeventfd(&eventfd, 0x10, 1, None, IoSpace::Pio)
+        .unwrap();
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x10, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+}
+
+#[test]
+fn irqfd() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    vm.create_irqchip().unwrap();
+
+    let eventfd = EventFd::new().unwrap();
+    vm.register_irqfd(&eventfd, 4, None).unwrap();
+
+    // The interrupt is injected asynchronously.
+    eventfd.write(1).unwrap();
+    for _ in 0..100 {
+        if master(&vm).irr & 0x10 != 0 {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+
+    let mut pic = master(&vm);
+    assert_eq!(pic.irr & 0x10, 0x10);
+    pic.irr = 0;
+    vm.set_irqchip(Irqchip::PicMaster(pic)).unwrap();
+
+    // Removal waits for pending injections, so nothing arrives after it.
+    vm.unregister_irqfd(&eventfd, 4).unwrap();
+    eventfd.write(1).unwrap();
+    sleep(Duration::from_millis(100));
+    assert_eq!(master(&vm).irr & 0x10, 0);
+}
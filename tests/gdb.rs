@@ -0,0 +1,188 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::*;
+
+use std::io::{Cursor, Read, Result, Write};
+
+const CODE: &[u8] = &[
+    0x00, 0xd8, // add %bl, %al
+    0xf4, // hlt
+];
+
+/// A `gdb` session played back from a script.
+struct Script {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Script {
+    /// Sends each packet and acknowledges each reply.
+    fn new(packets: &[&str]) -> Self {
+        let mut input = String::new();
+        for packet in packets {
+            let checksum = packet.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+            input += &format!("${}#{:02x}+", packet, checksum);
+        }
+
+        Script {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        }
+    }
+
+    /// The replies, without acknowledgements.
+    fn replies(&self) -> Vec<String> {
+        let output = String::from_utf8(self.output.clone()).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect()
+    }
+}
+
+impl Read for Script {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Script {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn session(packets: &[&str]) -> (Vec<String>, gdb::Resume, VirtualMachine) {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut code = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x1000)
+        .done()
+        .unwrap();
+    code[..CODE.len()].copy_from_slice(CODE);
+    vm.add_region(0, MemoryFlags::default(), 0x1000, code)
+        .unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x1000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut script = Script::new(packets);
+    let resume = gdb::Stub::new(&mut script)
+        .stopped(&mut vm, &mut cpu, None)
+        .unwrap();
+
+    (script.replies(), resume, vm)
+}
+
+#[test]
+fn registers_and_memory() {
+    let (replies, resume, _) = session(&["?", "g", "m1000,3", "m3000,1", "D"]);
+    assert_eq!(resume, gdb::Resume::Detached);
+
+    assert_eq!(replies[0], "S05");
+
+    // 17 64-bit registers, then eflags and six selectors of 32 bits.
+    assert_eq!(replies[1].len(), 17 * 16 + 7 * 8);
+    assert!(replies[1].starts_with("8877665544332211"));
+    assert_eq!(&replies[1][16 * 16..17 * 16], "0010000000000000");
+
+    assert_eq!(replies[2], "00d8f4");
+    assert_eq!(replies[3], "E14");
+    assert_eq!(replies[4], "OK");
+}
+
+#[test]
+fn software_breakpoint() {
+    let (replies, _, vm) = session(&["Z0,1002,1", "m1000,3", "D"]);
+    assert_eq!(replies, ["OK", "00d8f4", "OK"]);
+
+    // The breakpoint was hidden from `m` and removed on detach.
+    let mut byte = [0];
+    vm.read_memory(0x1002, &mut byte).unwrap();
+    assert_eq!(byte, [0xf4]);
+
+    let (replies, _, vm) = session(&["Z0,1002,1", "k"]);
+    assert_eq!(replies, ["OK"]);
+    vm.read_memory(0x1002, &mut byte).unwrap();
+    assert_eq!(byte, [0xcc]);
+
+    let (replies, _, vm) = session(&["Z0,1002,1", "z0,1002,1", "k"]);
+    assert_eq!(replies, ["OK", "OK"]);
+    vm.read_memory(0x1002, &mut byte).unwrap();
+    assert_eq!(byte, [0xf4]);
+}
+
+#[test]
+fn watchpoint_length() {
+    let (replies, resume, _) = session(&[
+        "Z2,2000,3",
+        "Z2,2000,10",
+        "Z2,2000,100",
+        "Z2,2002,4",
+        "Z2,2000,4",
+        "Z3,2008,8",
+        "k",
+    ]);
+
+    assert_eq!(replies, ["E22", "E22", "E22", "E22", "OK", "OK"]);
+    assert_eq!(resume, gdb::Resume::Killed);
+}
+
+#[test]
+fn malformed() {
+    let registers = format!("G{}00000000", "0".repeat(17 * 16));
+    let (replies, resume, _) = session(&[
+        "qSupported",
+        "m1000,2001",
+        "M1000,1:zz",
+        "M1000,2:\u{e9}",
+        "\u{e9}",
+        &registers,
+        "P11=00000000",
+        "m1000,3",
+        "D",
+    ]);
+    assert_eq!(resume, gdb::Resume::Detached);
+
+    // Replies to `m` must fit in the advertised packet size.
+    assert!(replies[0].starts_with("PacketSize=4000;"));
+    assert_eq!(replies[1], "E22");
+
+    // Bad hex digits, and an unknown command.
+    assert_eq!(replies[2], "E01");
+    assert_eq!(replies[3], "E01");
+    assert_eq!(replies[4], "");
+
+    // RFLAGS bit 1 must be set.
+    assert_eq!(replies[5], "E22");
+    assert_eq!(replies[6], "E22");
+
+    assert_eq!(replies[7], "00d8f4");
+    assert_eq!(replies[8], "OK");
+}
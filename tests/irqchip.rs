@@ -0,0 +1,353 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{self, Gate, Irqchip, IrqchipId, PicState, PitConfig};
+use ketuvim::irqchip::{GsiRouting, Ioapic, Msi, Pic};
+use ketuvim::util::map::{Access, Flags, Map, Protection};
+use ketuvim::*;
+
+#[test]
+fn ioapic() {
+    let mut ioapic = Ioapic::new();
+
+    // Route pin 3 to vector 0x30, level triggered, on APIC 1.
+    assert!(ioapic.write(0x00, 0x17).is_empty());
+    assert!(ioapic.write(0x10, 0x0100_0000).is_empty());
+    assert!(ioapic.write(0x00, 0x16).is_empty());
+    assert!(ioapic.write(0x10, 0x8030).is_empty());
+    assert_eq!(ioapic.read(0x10), 0x8030);
+
+    let msi = Msi {
+        address: 0xfee0_1000,
+        data: 0xc030,
+    };
+
+    // A level triggered interrupt is delivered once until the EOI.
+    assert_eq!(ioapic.set_irq(3, true), vec![msi]);
+    assert!(ioapic.set_irq(3, true).is_empty());
+    assert_eq!(ioapic.end_of_interrupt(0x30), vec![msi]);
+
+    ioapic.set_irq(3, false);
+    assert!(ioapic.end_of_interrupt(0x30).is_empty());
+}
+
+#[test]
+fn ioapic_edge() {
+    let mut ioapic = Ioapic::new();
+
+    // Route pin 4 to vector 0x40, edge triggered, on APIC 0.
+    assert!(ioapic.write(0x00, 0x18).is_empty());
+    assert!(ioapic.write(0x10, 0x40).is_empty());
+
+    let msi = Msi {
+        address: 0xfee0_0000,
+        data: 0x40,
+    };
+
+    // Only a rising edge is delivered, however long the pin stays high.
+    assert_eq!(ioapic.set_irq(4, true), vec![msi]);
+    assert!(ioapic.set_irq(4, true).is_empty());
+    assert!(ioapic.set_irq(4, false).is_empty());
+    assert_eq!(ioapic.set_irq(4, true), vec![msi]);
+
+    // A restored pin that is still high does not fire again.
+    assert!(ioapic.set_irq(4, true).is_empty());
+    let mut ioapic = Ioapic::with_levels(ioapic.state(), ioapic.levels());
+    assert!(ioapic.set_irq(4, true).is_empty());
+    assert_eq!(ioapic.levels(), 1 << 4);
+
+    // Pins that do not exist are ignored.
+    assert!(ioapic.set_irq(24, true).is_empty());
+    assert!(ioapic.set_irq(usize::MAX, true).is_empty());
+}
+
+#[test]
+fn pic() {
+    let mut pic = Pic::new();
+
+    // Initialize both chips as a standard cascade at vectors 0x20 and 0x28.
+    for (port, value) in &[
+        (0x20, 0x11),
+        (0x21, 0x20),
+        (0x21, 0x04),
+        (0x21, 0x01),
+        (0xa0, 0x11),
+        (0xa1, 0x28),
+        (0xa1, 0x02),
+        (0xa1, 0x01),
+        (0x21, 0x00),
+        (0xa1, 0x00),
+    ] {
+        pic.write(*port, *value);
+    }
+
+    // Lines that do not exist are ignored.
+    pic.set_irq(16, true);
+    assert!(!pic.pending());
+
+    pic.set_irq(9, true);
+    assert!(pic.pending());
+    assert_eq!(pic.acknowledge(), Some(0x29));
+    assert!(!pic.pending());
+
+    // IRQ 0 has a higher priority than the cascade that is in service.
+    pic.set_irq(0, true);
+    assert_eq!(pic.acknowledge(), Some(0x20));
+
+    // Non-specific EOIs to both chips.
+    pic.write(0x20, 0x20);
+    pic.write(0xa0, 0x20);
+    pic.write(0x20, 0x20);
+    pic.write(0x20, 0x0b);
+    assert_eq!(pic.read(0x20), 0);
+}
+
+fn kvm_irqchip() -> VirtualMachine {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    vm.create_irqchip().unwrap();
+    vm.create_pit2(PitConfig::default()).unwrap();
+    vm
+}
+
+fn pic_state(vm: &VirtualMachine, id: IrqchipId) -> PicState {
+    match vm.irqchip(id).unwrap() {
+        Irqchip::PicMaster(pic) | Irqchip::PicSlave(pic) => pic,
+        chip => panic!("unexpected chip: {:?}", chip),
+    }
+}
+
+#[test]
+fn kvm_irq_line() {
+    let vm = kvm_irqchip();
+
+    // An edge on an ISA line is latched by the PIC until acknowledged.
+    vm.irq_line(4, true).unwrap();
+    vm.irq_line(4, false).unwrap();
+    assert_eq!(pic_state(&vm, IrqchipId::PicMaster).irr & 0x10, 0x10);
+
+    vm.irq_line(10, true).unwrap();
+    vm.irq_line(10, false).unwrap();
+    assert_eq!(pic_state(&vm, IrqchipId::PicSlave).irr & 0x04, 0x04);
+}
+
+#[test]
+fn kvm_gsi_routing() {
+    let mut vm = kvm_irqchip();
+    let _cpu = VirtualCpu::new(&vm).unwrap();
+
+    // The table replaces the default routes, so ISA line 4 goes nowhere.
+    let routing = GsiRouting::new()
+        .irqchip(20, IrqchipId::PicMaster, 3)
+        .irqchip(20, IrqchipId::Ioapic, 3)
+        .msi(21, 0xfee0_0000, 0x30);
+    vm.set_gsi_routing(&routing).unwrap();
+
+    vm.irq_line(4, true).unwrap();
+    vm.irq_line(4, false).unwrap();
+    vm.irq_line(20, true).unwrap();
+    vm.irq_line(20, false).unwrap();
+    vm.irq_line(21, true).unwrap();
+    assert_eq!(pic_state(&vm, IrqchipId::PicMaster).irr & 0x18, 0x08);
+
+    // The LAPIC is software disabled out of reset and blocks the MSI.
+    assert!(!vm.signal_msi(0xfee0_0000, 0x30).unwrap());
+}
+
+#[test]
+fn kvm_irqchip_state() {
+    let mut vm = kvm_irqchip();
+
+    let mut master = pic_state(&vm, IrqchipId::PicMaster);
+    master.imr = 0xfb;
+    master.irq_base = 0x20;
+    vm.set_irqchip(Irqchip::PicMaster(master)).unwrap();
+    let master = pic_state(&vm, IrqchipId::PicMaster);
+    assert_eq!((master.imr, master.irq_base), (0xfb, 0x20));
+
+    let mut slave = pic_state(&vm, IrqchipId::PicSlave);
+    slave.irq_base = 0x28;
+    vm.set_irqchip(Irqchip::PicSlave(slave)).unwrap();
+    assert_eq!(pic_state(&vm, IrqchipId::PicSlave).irq_base, 0x28);
+
+    let mut ioapic = match vm.irqchip(IrqchipId::Ioapic).unwrap() {
+        Irqchip::Ioapic(ioapic) => ioapic,
+        chip => panic!("unexpected chip: {:?}", chip),
+    };
+    ioapic.id = 1 << 24;
+    ioapic.redirtbl[3] = 0x0100_0000_0001_8030;
+    vm.set_irqchip(Irqchip::Ioapic(ioapic)).unwrap();
+    match vm.irqchip(IrqchipId::Ioapic).unwrap() {
+        Irqchip::Ioapic(ioapic) => {
+            assert_eq!(ioapic.id, 1 << 24);
+            assert_eq!(ioapic.redirtbl[3], 0x0100_0000_0001_8030);
+        }
+        chip => panic!("unexpected chip: {:?}", chip),
+    }
+}
+
+#[test]
+fn kvm_pit_state() {
+    let mut vm = kvm_irqchip();
+
+    // Channel 0 as a rate generator.
+    let mut pit = vm.pit_state().unwrap();
+    pit.channels[0].count = 0x1234;
+    pit.channels[0].mode = 2;
+    pit.channels[0].rw_mode = 3;
+    vm.set_pit_state(pit).unwrap();
+
+    let pit = vm.pit_state().unwrap();
+    assert_eq!(pit.channels[0].count, 0x1234);
+    assert_eq!(pit.channels[0].mode, 2);
+    assert_eq!(pit.channels[0].rw_mode, 3);
+}
+
+const TABLES: u64 = 0x1000;
+const IDT: u64 = 0x9000;
+const CODE: u64 = 0xa000;
+const EXTERNAL: u64 = 0xa100;
+const LEVEL: u64 = 0xa200;
+
+const MAIN: &[u8] = &[
+    0xb8, 0x00, 0x00, 0xe0, 0xfe, // mov $0xfee00000, %eax
+    0xc7, 0x80, 0xf0, 0x00, 0x00, 0x00, 0xff, 0x01, 0x00, 0x00, // movl $0x1ff, 0xf0(%rax)
+    0xe6, 0x10, // out %al, $0x10
+    0xfb, // sti
+    0x90, // nop
+    0xe6, 0x11, // out %al, $0x11
+    0xe6, 0x12, // out %al, $0x12
+    0xe6, 0x13, // out %al, $0x13
+    0xf4, // hlt
+];
+
+const EXTERNAL_HANDLER: &[u8] = &[
+    0xe6, 0x20, // out %al, $0x20
+    0x48, 0xcf, // iretq
+];
+
+const LEVEL_HANDLER: &[u8] = &[
+    0xb8, 0x00, 0x00, 0xe0, 0xfe, // mov $0xfee00000, %eax
+    0xc7, 0x80, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // movl $0, 0xb0(%rax)
+    0xe6, 0x21, // out %al, $0x21
+    0x48, 0xcf, // iretq
+];
+
+/// Boots `code` in long mode with a split irqchip and an IDT with handlers
+/// for vectors 0x30 and 0x31.
+fn split(code: &[u8]) -> (VirtualMachine, VirtualCpu) {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    vm.enable_split_irqchip(24).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut memory = Map::<()>::build(Access::Shared)
+        .protection(Protection::READ | Protection::WRITE)
+        .flags(Flags::ANONYMOUS)
+        .extra(0x10000)
+        .done()
+        .unwrap();
+
+    for (vector, handler) in [(0x30, EXTERNAL), (0x31, LEVEL)].iter() {
+        let gate = Gate::interrupt(arch::CODE64_SELECTOR, *handler).long_descriptor();
+        let entry = &mut memory[IDT as usize + vector * 16..];
+        entry[..8].copy_from_slice(&gate[0].to_le_bytes());
+        entry[8..16].copy_from_slice(&gate[1].to_le_bytes());
+    }
+
+    memory[CODE as usize..][..code.len()].copy_from_slice(code);
+    memory[EXTERNAL as usize..][..EXTERNAL_HANDLER.len()].copy_from_slice(EXTERNAL_HANDLER);
+    memory[LEVEL as usize..][..LEVEL_HANDLER.len()].copy_from_slice(LEVEL_HANDLER);
+    vm.add_region(0, MemoryFlags::default(), 0, memory).unwrap();
+
+    arch::long_mode(&mut vm, &mut cpu, TABLES).unwrap();
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.idt.base = IDT;
+    sregs.idt.limit = 0x32 * 16 - 1;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: CODE,
+        rsp: 0xf000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    (vm, cpu)
+}
+
+fn out(reason: Reason) -> u16 {
+    match reason {
+        Reason::Io(ReasonIo::Out { port, .. }) => port,
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+}
+
+#[test]
+fn kvm_split_irqchip() {
+    let (mut vm, mut cpu) = split(MAIN);
+
+    assert_eq!(out(cpu.run().unwrap()), 0x10);
+    assert!(!cpu.ready_for_interrupt());
+    assert_eq!(out(cpu.run().unwrap()), 0x11);
+    assert!(cpu.ready_for_interrupt());
+
+    // An external interrupt is taken through the LAPIC's LINT0.
+    cpu.interrupt(0x30).unwrap();
+    assert_eq!(out(cpu.run().unwrap()), 0x20);
+    assert_eq!(out(cpu.run().unwrap()), 0x12);
+
+    // A level triggered vector routed from an IOAPIC pin exits on EOI.
+    let data = 0x31 | 1 << 14 | 1 << 15;
+    vm.set_gsi_routing(&GsiRouting::new().msi(2, 0xfee0_0000, data))
+        .unwrap();
+    assert!(vm.signal_msi(0xfee0_0000, data).unwrap());
+
+    let mut exits = Vec::new();
+    loop {
+        match cpu.run().unwrap() {
+            Reason::IoapicEoi { vector } => exits.push(vector as u16),
+            r => match out(r) {
+                0x13 => break,
+                port => exits.push(port),
+            },
+        }
+    }
+
+    // The EOI exit is only taken on the next entry into the guest.
+    exits.sort();
+    assert_eq!(exits, [0x21, 0x31]);
+}
+
+#[test]
+#[ignore = "needs interrupt window exits, which not every hypervisor provides"]
+fn kvm_interrupt_window() {
+    let (_vm, mut cpu) = split(&[
+        0xfb, // sti
+        0x90, // nop
+        0xf4, // hlt
+    ]);
+
+    cpu.request_interrupt_window(true);
+    match cpu.run().unwrap() {
+        Reason::InterruptWindow => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+    assert!(cpu.ready_for_interrupt());
+
+    cpu.request_interrupt_window(false);
+    cpu.interrupt(0x30).unwrap();
+    assert_eq!(out(cpu.run().unwrap()), 0x20);
+}
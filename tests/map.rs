@@ -0,0 +1,135 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::util::map::{Access, Advice, Flags, Map, Protection};
+
+use std::ffi::CStr;
+use std::fs::{read_to_string, File};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const PAGE: usize = 4096;
+
+fn build(guard: usize) -> Map<()> {
+    Map::<()>::build(Access::Private)
+        .protection(Protection::READ | Protection::WRITE)
+        .flags(Flags::ANONYMOUS)
+        .extra(PAGE)
+        .guard(guard)
+        .done()
+        .unwrap()
+}
+
+fn addr(map: &Map<()>) -> usize {
+    &**map as *const () as usize
+}
+
+/// The permissions of the mapping holding `addr`, as in `/proc/self/maps`.
+fn perms(addr: usize) -> String {
+    for line in read_to_string("/proc/self/maps").unwrap().lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().unwrap();
+        let perms = fields.next().unwrap();
+
+        let mut bounds = range
+            .split('-')
+            .map(|n| usize::from_str_radix(n, 16).unwrap());
+        let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+        if start <= addr && addr < end {
+            return perms.to_string();
+        }
+    }
+
+    panic!("{:x} is not mapped", addr);
+}
+
+/// A page of memfd, sealed with `seals`.
+fn memfd(seals: i32) -> File {
+    let name = CStr::from_bytes_with_nul(b"ketuvim\0").unwrap();
+    let file = unsafe {
+        let fd = libc::memfd_create(name.as_ptr(), libc::MFD_ALLOW_SEALING);
+        assert!(fd >= 0);
+        File::from_raw_fd(fd)
+    };
+
+    file.set_len(PAGE as u64).unwrap();
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) };
+    assert_eq!(ret, 0);
+
+    file
+}
+
+fn shared(file: &File) -> Map<()> {
+    Map::<()>::build(Access::Shared)
+        .protection(Protection::READ | Protection::WRITE)
+        .file(file, 0)
+        .extra(PAGE)
+        .done()
+        .unwrap()
+}
+
+#[test]
+fn failure() {
+    let map = Map::<()>::build(Access::Private)
+        .protection(Protection::READ)
+        .flags(Flags::ANONYMOUS)
+        .extra(1 << 62)
+        .done();
+    assert!(map.is_err());
+}
+
+#[test]
+fn guard() {
+    let map = build(1);
+    let addr = addr(&map);
+
+    assert_eq!(perms(addr - PAGE), "---p");
+    assert_eq!(perms(addr), "rw-p");
+    assert_eq!(perms(addr + PAGE), "---p");
+}
+
+#[test]
+fn guard_address() {
+    // Find a free range with plenty of room around the hint.
+    let free = addr(&build(4));
+    let map = Map::<()>::build(Access::Private)
+        .protection(Protection::READ | Protection::WRITE)
+        .flags(Flags::ANONYMOUS)
+        .extra(PAGE)
+        .address(free)
+        .guard(1)
+        .done()
+        .unwrap();
+
+    assert_eq!(addr(&map), free);
+    assert_eq!(perms(free - PAGE), "---p");
+}
+
+#[test]
+fn lock_advise_protect() {
+    let mut map = build(0);
+    map.lock().unwrap();
+    map.advise(Advice::DontDump).unwrap();
+    map.unlock().unwrap();
+
+    map.protect(Protection::READ).unwrap();
+    assert_eq!(perms(addr(&map)), "r--p");
+}
+
+#[test]
+fn truncatable() {
+    assert!(!build(0).is_truncatable());
+    assert!(shared(&memfd(0)).is_truncatable());
+    assert!(shared(&memfd(libc::F_SEAL_GROW)).is_truncatable());
+    assert!(!shared(&memfd(libc::F_SEAL_SHRINK)).is_truncatable());
+}
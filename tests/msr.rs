@@ -0,0 +1,105 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{MsrAccess, MsrExitReasons, MsrFilter};
+use ketuvim::*;
+
+const IA32_TSC: u32 = 0x10;
+
+const CODE: &[u8] = &[
+    0xe6, 0x20, // out %al, $0x20
+    0x66, 0xb9, 0x10, 0x00, 0x00, 0x00, // mov $0x10, %ecx
+    0x0f, 0x32, // rdmsr
+    0xf4, // hlt
+];
+
+const GP_HANDLER: &[u8] = &[
+    0xe6, 0x10, // out %al, $0x10
+    0xf4, // hlt
+];
+
+#[test]
+fn reject() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+    let mut cpu = VirtualCpu::new(&vm).unwrap();
+
+    let mut mem = util::map::Map::<()>::build(util::map::Access::Shared)
+        .protection(util::map::Protection::READ | util::map::Protection::WRITE)
+        .flags(util::map::Flags::ANONYMOUS)
+        .extra(0x2000)
+        .done()
+        .unwrap();
+
+    // The real mode IVT entry for #GP points at the handler.
+    mem[13 * 4..][..4].copy_from_slice(&[0x00, 0x11, 0x00, 0x00]);
+    mem[0x1000..][..CODE.len()].copy_from_slice(CODE);
+    mem[0x1100..][..GP_HANDLER.len()].copy_from_slice(GP_HANDLER);
+    vm.add_region(0, MemoryFlags::default(), 0, mem).unwrap();
+
+    vm.enable_user_space_msr(MsrExitReasons::FILTER).unwrap();
+    vm.set_msr_filter(&MsrFilter::allow().deny_range(IA32_TSC, 1, MsrAccess::READ))
+        .unwrap();
+
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rsp: 0x2000,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    // Only an MSR exit can be rejected.
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x20, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+    assert!(cpu.reject_msr().is_err());
+
+    match cpu.run().unwrap() {
+        Reason::MsrRead { index, .. } => assert_eq!(index, IA32_TSC),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+    cpu.reject_msr().unwrap();
+
+    // The guest takes #GP instead of completing the read.
+    match cpu.run().unwrap() {
+        Reason::Io(ReasonIo::Out { port: 0x10, .. }) => (),
+        r => panic!("Unexpected exit reason: {:?}", r),
+    }
+}
+
+#[test]
+fn invalid_filter() {
+    let kvm = Kvm::open().unwrap();
+    let mut vm = VirtualMachine::new(&kvm).unwrap();
+
+    // A bitmap that does not cover the range.
+    let filter = MsrFilter::deny().range(0, 9, MsrAccess::READ, vec![0xff]);
+    assert!(vm.set_msr_filter(&filter).is_err());
+
+    let filter = MsrFilter::deny().allow_range(0, MsrFilter::MAX_MSRS + 1, MsrAccess::READ);
+    assert!(vm.set_msr_filter(&filter).is_err());
+
+    // Bitmaps that do not fill a whole long are padded.
+    let filter = MsrFilter::deny()
+        .range(0, 8, MsrAccess::READ, vec![0xff])
+        .allow_range(0x100, MsrFilter::MAX_MSRS, MsrAccess::WRITE);
+    vm.set_msr_filter(&filter).unwrap();
+}
@@ -0,0 +1,95 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{walk, SpecialRegisters, Translation};
+
+const P: u64 = 1 << 0;
+const RW: u64 = 1 << 1;
+const US: u64 = 1 << 2;
+const PS: u64 = 1 << 7;
+
+fn entry(mem: &mut [u8], table: u64, index: u64, value: u64) {
+    let offset = (table + index * 8) as usize;
+    mem[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn memory() -> Vec<u8> {
+    let mut mem = vec![0u8; 0x6000];
+
+    // PML5 (0x5000) -> PML4 (0x1000) -> PDPT (0x2000) -> PD (0x3000) -> PT (0x4000)
+    entry(&mut mem, 0x5000, 0, 0x1000 | P | RW | US);
+    entry(&mut mem, 0x1000, 0, 0x2000 | P | RW | US);
+    entry(&mut mem, 0x2000, 0, 0x3000 | P | RW | US);
+    entry(&mut mem, 0x2000, 1, 0x8000_0000 | P | PS);
+    entry(&mut mem, 0x3000, 0, 0x4000 | P | RW | US);
+    entry(&mut mem, 0x3000, 1, 0x0060_0000 | P | RW | PS);
+    entry(&mut mem, 0x4000, 1, 0x9000 | P | US);
+
+    mem
+}
+
+fn sregs(la57: bool) -> SpecialRegisters {
+    SpecialRegisters {
+        cr0: 1 << 31 | 1,
+        cr3: if la57 { 0x5000 } else { 0x1000 },
+        cr4: 1 << 5 | (la57 as u64) << 12,
+        efer: 1 << 8 | 1 << 10,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn four_level() {
+    let mem = memory();
+    let sregs = sregs(false);
+
+    let tr = walk(&mem[..], &sregs, 0x1234).unwrap();
+    assert_eq!(
+        tr,
+        Translation {
+            physical_address: 0x9234,
+            valid: true,
+            writeable: false,
+            usermode: true,
+        }
+    );
+
+    let tr = walk(&mem[..], &sregs, 0x0020_5678).unwrap();
+    assert_eq!(tr.physical_address, 0x0060_5678);
+    assert!(tr.writeable && !tr.usermode);
+
+    let tr = walk(&mem[..], &sregs, 0x4012_3456).unwrap();
+    assert_eq!(tr.physical_address, 0x8012_3456);
+
+    assert!(!walk(&mem[..], &sregs, 0x2000).unwrap().valid);
+    assert!(!walk(&mem[..], &sregs, 0x80_0000_0000).unwrap().valid);
+}
+
+#[test]
+fn five_level() {
+    let mem = memory();
+    let sregs = sregs(true);
+
+    assert_eq!(
+        walk(&mem[..], &sregs, 0x1234).unwrap().physical_address,
+        0x9234
+    );
+    assert!(!walk(&mem[..], &sregs, 1 << 48).unwrap().valid);
+}
+
+#[test]
+fn unpaged() {
+    let tr = walk(&[][..], &SpecialRegisters::default(), 0x1234).unwrap();
+    assert_eq!(tr.physical_address, 0x1234);
+}
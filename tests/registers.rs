@@ -0,0 +1,89 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{Cr0, Cr4, Efer, Registers, Rflags, SpecialRegisters, XCr0};
+
+fn long_mode() -> SpecialRegisters {
+    let mut sregs = SpecialRegisters::default();
+    sregs.set_cr0(Cr0::PE | Cr0::PG);
+    sregs.set_cr4(Cr4::PAE);
+    sregs.set_efer(Efer::LME | Efer::LMA);
+    sregs.cs.l = 1;
+    sregs
+}
+
+#[test]
+fn accessors() {
+    let sregs = long_mode();
+    assert_eq!(sregs.cr0, 1 << 31 | 1);
+    assert_eq!(sregs.efer, 1 << 8 | 1 << 10);
+    assert!(sregs.cr4().contains(Cr4::PAE));
+
+    let mut regs = Registers::default();
+    regs.set_rflags(Rflags::default());
+    assert_eq!(regs.rflags, 0x2);
+}
+
+#[test]
+fn special_registers() {
+    assert!(SpecialRegisters::default().validate().is_ok());
+    assert!(long_mode().validate().is_ok());
+
+    let mut sregs = long_mode();
+    sregs.set_cr0(Cr0::PG);
+    assert!(sregs.validate().is_err());
+
+    let mut sregs = long_mode();
+    sregs.set_cr4(Cr4::empty());
+    assert!(sregs.validate().is_err());
+
+    let mut sregs = long_mode();
+    sregs.set_efer(Efer::LME);
+    assert!(sregs.validate().is_err());
+
+    let mut sregs = SpecialRegisters::default();
+    sregs.set_efer(Efer::LMA);
+    assert!(sregs.validate().is_err());
+
+    let mut sregs = long_mode();
+    sregs.cs.db = 1;
+    assert!(sregs.validate().is_err());
+
+    let mut sregs = SpecialRegisters::default();
+    sregs.cs.l = 1;
+    assert!(sregs.validate().is_err());
+}
+
+#[test]
+fn registers() {
+    let mut regs = Registers::default();
+    assert!(regs.validate().is_err());
+
+    regs.rflags = 0x2;
+    assert!(regs.validate().is_ok());
+
+    regs.rflags |= 1 << 3;
+    assert!(regs.validate().is_err());
+}
+
+#[test]
+fn xcr0() {
+    assert!(XCr0::default().validate().is_ok());
+    assert!((XCr0::X87 | XCr0::SSE | XCr0::AVX).validate().is_ok());
+    assert!(XCr0::SSE.validate().is_err());
+    assert!((XCr0::X87 | XCr0::AVX).validate().is_err());
+    assert!((XCr0::X87 | XCr0::SSE | XCr0::AVX | XCr0::OPMASK)
+        .validate()
+        .is_err());
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use codicon::Decoder;
-use ketuvim::{arch, util::map, Kvm, MemoryFlags, Reason, ReasonIo, VirtualCpu, VirtualMachine};
+use ketuvim::{arch, util::map, Kvm, MemoryFlags, Reason, ReasonIo, VirtualMachine};
 use std::convert::TryFrom;
 
 const CODE: &[u8] = &[
@@ -89,10 +89,10 @@ fn test() {
     // Server injects the secret into the VM.
     let len = secret.ciphertext.len() as u32;
     launch.inject(secret, addr, len).unwrap();
-    let (_, vm) = launch.finish().unwrap();
+    let mut guest = launch.finish().unwrap();
 
     // Setup special registers.
-    let mut cpu = VirtualCpu::new(&vm).unwrap();
+    let cpu = guest.create_cpu().unwrap();
     let mut sregs = cpu.special_registers().unwrap();
     sregs.cs.base = 0;
     sregs.cs.selector = 0;
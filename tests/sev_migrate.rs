@@ -0,0 +1,146 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use common::{Event, StandIn};
+use ketuvim::sev::sev::{certs, launch};
+use ketuvim::sev::{Code, Guest, Initialized, Launch, Receive, Send, Target};
+use ketuvim::*;
+
+fn vm() -> VirtualMachine {
+    common::vm(&[(0x10000, 0x1000), (0, 0x3000)])
+}
+
+fn pdh() -> certs::sev::Certificate {
+    let mut cert = [0u8; 0x824];
+    cert[0x00..0x04].copy_from_slice(&1u32.to_le_bytes());
+    unsafe { std::ptr::read(cert.as_ptr() as *const _) }
+}
+
+fn launch(vm: VirtualMachine, fw: &StandIn) -> Guest<StandIn> {
+    let start = launch::Start {
+        policy: launch::Policy::default(),
+        cert: pdh(),
+        session: unsafe { std::mem::zeroed() },
+    };
+
+    let launch = Launch::<Initialized, _>::with_firmware(vm, fw.clone()).unwrap();
+    let guest = launch
+        .start(start)
+        .unwrap()
+        .measure()
+        .unwrap()
+        .finish()
+        .unwrap();
+    assert_eq!(
+        fw.events(),
+        [
+            Event::Command(Code::Init),
+            Event::Command(Code::LaunchStart),
+            Event::Command(Code::LaunchMeasure),
+            Event::Command(Code::LaunchFinish),
+        ]
+    );
+
+    guest
+}
+
+#[test]
+fn migrate() {
+    let mut source = vm();
+    let low: Vec<u8> = (0..0x3000).map(|i| (i * 7) as u8).collect();
+    source.write_memory(0, &low).unwrap();
+    source.write_memory(0x10000, &[0xcc; 0x1000]).unwrap();
+
+    let fw = StandIn::default();
+    let source = launch(source, &fw);
+    let handle = source.handle();
+
+    let pdh = pdh();
+    let target = Target {
+        pdh: &pdh,
+        plat_certs: &[],
+        amd_certs: &[],
+    };
+
+    let mut stream = Vec::new();
+    let mut send = Send::new(source).start(&pdh, target, &mut stream).unwrap();
+    send.update_all(&mut stream).unwrap();
+    send.finish(&mut stream).unwrap();
+
+    // The guest that was sent is gone from here.
+    assert_eq!(
+        fw.events(),
+        [
+            Event::Command(Code::SendStart),
+            Event::Command(Code::SendFinish),
+            Event::Decommission(handle),
+        ]
+    );
+
+    let receive = Receive::<Initialized, _>::with_firmware(vm(), fw.clone()).unwrap();
+    let mut reader = &stream[..];
+    let mut receive = receive.start(&mut reader).unwrap();
+    receive.update_all(&mut reader).unwrap();
+    let target = receive.finish().unwrap();
+    assert!(reader.is_empty());
+    assert_eq!(
+        fw.events(),
+        [
+            Event::Command(Code::Init),
+            Event::Command(Code::ReceiveStart),
+            Event::Command(Code::ReceiveFinish),
+        ]
+    );
+
+    let mut buf = vec![0u8; 0x3000];
+    target.vm().read_memory(0, &mut buf).unwrap();
+    assert_eq!(buf, low);
+
+    let mut buf = vec![0u8; 0x1000];
+    target.vm().read_memory(0x10000, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0xcc));
+}
+
+#[test]
+fn cancel() {
+    let fw = StandIn::default();
+    let source = launch(vm(), &fw);
+    let handle = source.handle();
+
+    let pdh = pdh();
+    let target = Target {
+        pdh: &pdh,
+        plat_certs: &[],
+        amd_certs: &[],
+    };
+
+    let mut stream = Vec::new();
+    let send = Send::new(source).start(&pdh, target, &mut stream).unwrap();
+
+    // The guest that stays here is only decommissioned when dropped.
+    let guest = send.cancel().unwrap();
+    assert_eq!(guest.handle(), handle);
+    assert_eq!(
+        fw.events(),
+        [
+            Event::Command(Code::SendStart),
+            Event::Command(Code::SendCancel),
+        ]
+    );
+
+    drop(guest);
+    assert_eq!(fw.events(), [Event::Decommission(handle)]);
+}
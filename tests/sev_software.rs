@@ -0,0 +1,505 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use common::memory;
+use ketuvim::sev::sev::{
+    certs, firmware,
+    firmware::{Error, Indeterminate},
+    launch,
+};
+use ketuvim::sev::{Code, Digest, Firmware, GuestState, Handle, Initialized, Launch, Software};
+use ketuvim::{arch, util::map, MemoryFlags, Reason, ReasonIo, VirtualMachine};
+
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{encrypt, Cipher};
+
+use std::cell::{Cell, RefCell};
+use std::fs::OpenOptions;
+use std::mem::{size_of, size_of_val};
+use std::os::raw::c_void;
+use std::slice::from_raw_parts;
+
+const CODE: &[u8] = &[
+    0xba, 0xf8, 0x03, // mov $0x3f8, %dx
+    0x00, 0xd8, // add %bl, %al
+    0xee, // out %al, (%dx)
+    0xf4, // hlt
+];
+
+const BUILD: firmware::Build = firmware::Build {
+    version: firmware::Version(0, 17),
+    build: 22,
+};
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let key = PKey::hmac(key).unwrap();
+    let mut sig = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    for part in parts {
+        sig.update(part).unwrap();
+    }
+
+    let mut mac = [0u8; 32];
+    sig.sign(&mut mac).unwrap();
+    mac
+}
+
+fn kdf(key: &[u8], label: &str, context: &[u8]) -> [u8; 16] {
+    let mac = hmac(
+        key,
+        &[
+            &1u32.to_le_bytes(),
+            label.as_bytes(),
+            &[0],
+            context,
+            &128u32.to_le_bytes(),
+        ],
+    );
+
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&mac[..16]);
+    out
+}
+
+fn ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+    encrypt(Cipher::aes_128_ctr(), key, Some(iv), data).unwrap()
+}
+
+fn random<T: Default + AsMut<[u8]>>() -> T {
+    let mut buf = T::default();
+    rand_bytes(buf.as_mut()).unwrap();
+    buf
+}
+
+/// The guest owner's side of the launch.
+struct Owner {
+    policy: launch::Policy,
+    tek: [u8; 16],
+    tik: [u8; 16],
+}
+
+impl Owner {
+    fn new() -> Self {
+        Owner {
+            policy: launch::Policy {
+                flags: launch::PolicyFlags::NO_DEBUG | launch::PolicyFlags::NO_KEY_SHARING,
+                minfw: firmware::Version(0, 0),
+            },
+            tek: random(),
+            tik: random(),
+        }
+    }
+
+    fn policy(&self) -> &[u8] {
+        unsafe {
+            from_raw_parts(
+                &self.policy as *const _ as *const u8,
+                size_of_val(&self.policy),
+            )
+        }
+    }
+
+    /// An SEV format certificate carrying our public ECDH key.
+    fn certificate(key: &EcKey<Private>) -> certs::sev::Certificate {
+        let mut cert = [0u8; 0x824];
+        cert[0x00..0x04].copy_from_slice(&1u32.to_le_bytes());
+        cert[0x08..0x0c].copy_from_slice(&0x1003u32.to_le_bytes());
+        cert[0x0c..0x10].copy_from_slice(&0x003u32.to_le_bytes());
+        cert[0x10..0x14].copy_from_slice(&2u32.to_le_bytes());
+
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let mut ctx = openssl::bn::BigNumContext::new().unwrap();
+        let mut x = openssl::bn::BigNum::new().unwrap();
+        let mut y = openssl::bn::BigNum::new().unwrap();
+        key.public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut ctx)
+            .unwrap();
+
+        for (offset, n) in [(0x14, x), (0x5c, y)].iter() {
+            let mut le = n.to_vec_padded(48).unwrap();
+            le.reverse();
+            cert[*offset..][..48].copy_from_slice(&le);
+        }
+
+        assert_eq!(size_of::<certs::sev::Certificate>(), cert.len());
+        unsafe { std::ptr::read(cert.as_ptr() as *const _) }
+    }
+
+    fn start(&self, pdh: &EcKey<openssl::pkey::Public>, tamper: bool) -> launch::Start {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+
+        let ours = PKey::from_ec_key(key.clone()).unwrap();
+        let theirs = PKey::from_ec_key(pdh.clone()).unwrap();
+        let mut deriver = Deriver::new(&ours).unwrap();
+        deriver.set_peer(&theirs).unwrap();
+        let mut z = deriver.derive_to_vec().unwrap();
+        z.reverse();
+
+        let nonce: [u8; 16] = random();
+        let master = kdf(&z, "sev-master-secret", &nonce);
+        let kek = kdf(&master, "sev-kek", &[]);
+        let kik = kdf(&master, "sev-kik", &[]);
+
+        let wrap_iv: [u8; 16] = random();
+        let mut wrap_tk = [0u8; 32];
+        wrap_tk.copy_from_slice(&ctr(&kek, &wrap_iv, &[self.tek, self.tik].concat()));
+
+        let mut policy_mac = hmac(&self.tik, &[self.policy()]);
+        if tamper {
+            policy_mac[0] ^= 1;
+        }
+
+        launch::Start {
+            policy: self.policy,
+            cert: Self::certificate(&key),
+            session: launch::Session {
+                nonce,
+                wrap_tk,
+                wrap_iv,
+                wrap_mac: hmac(&kik, &[&wrap_tk]),
+                policy_mac,
+            },
+        }
+    }
+
+    fn secret(&self, msr: &launch::Measurement, data: &[u8]) -> launch::Secret {
+        let flags = launch::HeaderFlags::default();
+        let iv: [u8; 16] = random();
+        let ciphertext = ctr(&self.tek, &iv, data);
+        let len = (ciphertext.len() as u32).to_le_bytes();
+
+        let mac = hmac(
+            &self.tik,
+            &[
+                &[0x01],
+                &flags.bits().to_le_bytes(),
+                &iv,
+                &len,
+                &len,
+                &ciphertext,
+                &msr.measure,
+            ],
+        );
+
+        launch::Secret {
+            header: launch::Header { flags, iv, mac },
+            ciphertext,
+        }
+    }
+}
+
+fn vm() -> (VirtualMachine, u64) {
+    let mut vm = common::vm(&[]);
+    let code = memory(0x1000);
+    let addr = &*code as *const () as u64;
+    vm.add_region(0, MemoryFlags::default(), 0x1000, code)
+        .unwrap();
+    (vm, addr)
+}
+
+#[test]
+fn launch() {
+    let owner = Owner::new();
+    let fw = Software::new(BUILD).unwrap();
+    let start = owner.start(&fw.pdh().unwrap(), false);
+
+    // Server spins up the VM and takes a measurement.
+    let (vm, addr) = vm();
+    let launch = Launch::<Initialized, _>::with_firmware(vm, fw).unwrap();
+    let mut launch = launch.start(start).unwrap();
+    launch.update_data(b"measured").unwrap();
+    let launch = launch.measure().unwrap();
+    let measurement = launch.measurement();
+
+    // The owner predicts the measurement offline and checks it.
+    let mut digest = Digest::new();
+    digest.update_data(b"measured");
+    let expected = digest
+        .measurement(BUILD, owner.policy, &owner.tik, measurement.mnonce)
+        .unwrap();
+    assert_eq!(expected, measurement);
+
+    // Server injects the owner's secret into the VM.
+    let secret = owner.secret(&measurement, CODE);
+    let len = secret.ciphertext.len() as u32;
+    launch.inject(secret, addr, len).unwrap();
+    let mut guest = launch.finish().unwrap();
+
+    let cpu = guest.create_cpu().unwrap();
+    let mut sregs = cpu.special_registers().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    cpu.set_special_registers(sregs).unwrap();
+
+    cpu.set_registers(arch::Registers {
+        rip: 0x1000,
+        rax: 2,
+        rbx: 2,
+        rflags: 0x2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    loop {
+        match cpu.run().unwrap() {
+            Reason::Io(ReasonIo::Out { port: 0x03f8, data }) => assert_eq!(data, &[4]),
+            Reason::Halt => break,
+            r => panic!("Unsupported exit reason: {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn tampered() {
+    let owner = Owner::new();
+    let fw = Software::new(BUILD).unwrap();
+    let start = owner.start(&fw.pdh().unwrap(), true);
+
+    let (vm, _) = vm();
+    let launch = Launch::<Initialized, _>::with_firmware(vm, fw).unwrap();
+    assert!(launch.start(start).is_err());
+}
+
+#[test]
+fn status() {
+    let owner = Owner::new();
+    let fw = Software::new(BUILD).unwrap();
+
+    let launch = Launch::<Initialized, _>::with_firmware(vm().0, &fw).unwrap();
+    let launch = launch
+        .start(owner.start(&fw.pdh().unwrap(), false))
+        .unwrap();
+    let guest = launch.measure().unwrap().finish().unwrap();
+
+    let status = guest.status().unwrap();
+    assert_eq!(status.handle, guest.handle());
+    assert_eq!(status.policy, owner.policy);
+    assert_eq!(status.state, GuestState::Running);
+
+    // The firmware only has room for one guest until it is decommissioned.
+    assert!(Launch::<Initialized, _>::with_firmware(vm().0, &fw).is_err());
+
+    drop(guest);
+    Launch::<Initialized, _>::with_firmware(vm().0, &fw).unwrap();
+}
+
+#[test]
+fn debug() {
+    let mut owner = Owner::new();
+    owner.policy.flags.remove(launch::PolicyFlags::NO_DEBUG);
+    let fw = Software::new(BUILD).unwrap();
+    let start = owner.start(&fw.pdh().unwrap(), false);
+
+    let launch = Launch::<Initialized, _>::with_firmware(vm().0, fw).unwrap();
+    let mut guest = launch
+        .start(start)
+        .unwrap()
+        .measure()
+        .unwrap()
+        .finish()
+        .unwrap();
+
+    guest.write_encrypted(0x1ff0, b"debugged").unwrap();
+    let mut buf = [0u8; 8];
+    guest.read_encrypted(0x1ff0, &mut buf).unwrap();
+    assert_eq!(&buf, b"debugged");
+
+    // The region ends at 0x2000.
+    guest.write_encrypted(0x1ffc, b"debugged").unwrap_err();
+}
+
+#[test]
+fn no_debug() {
+    let owner = Owner::new();
+    let fw = Software::new(BUILD).unwrap();
+    let start = owner.start(&fw.pdh().unwrap(), false);
+
+    let launch = Launch::<Initialized, _>::with_firmware(vm().0, fw).unwrap();
+    let mut guest = launch
+        .start(start)
+        .unwrap()
+        .measure()
+        .unwrap()
+        .finish()
+        .unwrap();
+
+    let mut buf = [0u8; 8];
+    assert!(guest.read_encrypted(0x1000, &mut buf).is_err());
+    assert!(guest.write_encrypted(0x1000, &buf).is_err());
+}
+
+#[test]
+fn encrypt_all() {
+    let owner = Owner::new();
+    let fw = Software::new(BUILD).unwrap();
+    let start = owner.start(&fw.pdh().unwrap(), false);
+
+    // Regions are measured by address, not in the order they were added.
+    let (mut vm, _) = vm();
+    vm.add_region(0, MemoryFlags::default(), 0, memory(0x1000))
+        .unwrap();
+    vm.write_memory(0, &[0x11; 0x1000]).unwrap();
+    vm.write_memory(0x1000, CODE).unwrap();
+
+    let launch = Launch::<Initialized, _>::with_firmware(vm, fw).unwrap();
+    let mut launch = launch.start(start).unwrap();
+    launch.encrypt_all().unwrap();
+
+    // Regions must exist, and are never measured twice.
+    assert!(launch.encrypt_region(0, 0).is_err());
+    assert!(launch.encrypt_region(0, 2).is_err());
+    assert!(launch.encrypt_all().is_err());
+    let measurement = launch.measure().unwrap().measurement();
+
+    let mut code = vec![0u8; 0x1000];
+    code[..CODE.len()].copy_from_slice(CODE);
+
+    let mut digest = Digest::new();
+    digest.update_data(&[0x11; 0x1000]);
+    digest.update_data(&code);
+    let expected = digest
+        .measurement(BUILD, owner.policy, &owner.tik, measurement.mnonce)
+        .unwrap();
+    assert_eq!(expected, measurement);
+}
+
+/// Records the memory that the firmware is asked to pin.
+struct Pinning {
+    fw: Software,
+    pinned: RefCell<Vec<(u64, usize)>>,
+    refuse: Cell<bool>,
+}
+
+impl Firmware for Pinning {
+    unsafe fn cmd(
+        &self,
+        vm: &VirtualMachine,
+        code: Code,
+        data: *mut c_void,
+    ) -> Result<(), Indeterminate<Error>> {
+        self.fw.cmd(vm, code, data)
+    }
+
+    fn decommission(&self, vm: &VirtualMachine, handle: Handle) {
+        self.fw.decommission(vm, handle)
+    }
+
+    fn register_region(&self, _: &VirtualMachine, mem: &[u8]) -> Result<(), Indeterminate<Error>> {
+        if self.refuse.get() {
+            return Err(std::io::Error::from(std::io::ErrorKind::Other).into());
+        }
+
+        self.pinned
+            .borrow_mut()
+            .push((mem.as_ptr() as u64, mem.len()));
+        Ok(())
+    }
+
+    fn unregister_region(
+        &self,
+        _: &VirtualMachine,
+        mem: &[u8],
+    ) -> Result<(), Indeterminate<Error>> {
+        let region = (mem.as_ptr() as u64, mem.len());
+        self.pinned.borrow_mut().retain(|r| *r != region);
+        Ok(())
+    }
+}
+
+fn file_backed(name: &str) -> map::Map<()> {
+    let name = format!("ketuvim-{}-{}", name, std::process::id());
+    let path = std::env::temp_dir().join(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.set_len(0x1000).unwrap();
+
+    map::Map::<()>::build(map::Access::Shared)
+        .protection(map::Protection::READ | map::Protection::WRITE)
+        .file(&file, 0)
+        .extra(0x1000)
+        .done()
+        .unwrap()
+}
+
+#[test]
+fn pinning() {
+    let owner = Owner::new();
+    let fw = Pinning {
+        fw: Software::new(BUILD).unwrap(),
+        pinned: RefCell::new(Vec::new()),
+        refuse: Cell::new(false),
+    };
+    let start = owner.start(&fw.fw.pdh().unwrap(), false);
+
+    let (vm, addr) = vm();
+    let launch = Launch::<Initialized, _>::with_firmware(vm, &fw).unwrap();
+    assert_eq!(*fw.pinned.borrow(), [(addr, 0x1000)]);
+
+    let launch = launch.start(start).unwrap();
+    let mut guest = launch.measure().unwrap().finish().unwrap();
+
+    // Regions added later are pinned too, and must not be file-backed.
+    assert!(guest
+        .vm_mut()
+        .add_region(0, MemoryFlags::default(), 0x2000, memory(0x1000))
+        .is_err());
+    assert!(guest
+        .add_region(0, MemoryFlags::default(), 0x2000, file_backed("pinning"))
+        .is_err());
+    guest
+        .add_region(0, MemoryFlags::default(), 0x2000, memory(0x1000))
+        .unwrap();
+    assert_eq!(fw.pinned.borrow().len(), 2);
+
+    // A region that cannot be pinned is taken out of the guest again.
+    fw.refuse.set(true);
+    assert!(guest
+        .add_region(0, MemoryFlags::default(), 0x3000, memory(0x1000))
+        .is_err());
+    assert!(guest.vm().read_memory(0x3000, &mut [0]).is_err());
+
+    fw.refuse.set(false);
+    let slot = guest
+        .add_region(0, MemoryFlags::default(), 0x3000, memory(0x1000))
+        .unwrap();
+    assert_eq!(slot, 2);
+    assert_eq!(fw.pinned.borrow().len(), 3);
+
+    drop(guest);
+    assert!(fw.pinned.borrow().is_empty());
+}
+
+#[test]
+fn file_backed_launch() {
+    let (mut vm, _) = vm();
+    vm.add_region(0, MemoryFlags::default(), 0x2000, file_backed("launch"))
+        .unwrap();
+
+    let fw = Software::new(BUILD).unwrap();
+    assert!(Launch::<Initialized, _>::with_firmware(vm, fw).is_err());
+}
@@ -0,0 +1,89 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use common::{memory, Event, StandIn};
+use ketuvim::sev::*;
+use ketuvim::*;
+
+fn vm() -> VirtualMachine {
+    common::vm(&[(0, 0x4000)])
+}
+
+#[test]
+fn launch() {
+    let fw = StandIn::default();
+
+    let launch = Launch::<SnpInitialized, _>::with_firmware(vm(), fw.clone()).unwrap();
+    let mut launch = launch
+        .start(SnpPolicy {
+            flags: SnpPolicyFlags::SMT,
+            abi_major: 1,
+            abi_minor: 51,
+        })
+        .unwrap();
+
+    let mut pages = vec![0u8; 0x2000];
+    pages[0] = 0xaa;
+    pages[0x1000] = 0xbb;
+    launch.update(0x1000, PageType::Normal, &pages).unwrap();
+    launch
+        .update(0x3000, PageType::Cpuid, &pages[..0x1000])
+        .unwrap();
+
+    assert!(launch.update(0x1800, PageType::Normal, &pages).is_err());
+    assert!(launch.update(0x1000, PageType::Zero, &pages[..10]).is_err());
+    launch.create_cpu().unwrap();
+
+    let mut guest = launch
+        .finish(SnpFinish {
+            host_data: [7; 32],
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(guest.cpus_mut().len(), 1);
+    let handle = guest.handle();
+    drop(guest);
+
+    assert_eq!(
+        fw.events(),
+        vec![
+            Event::Command(Code::Init2),
+            Event::SnpStart(0x3_0133),
+            Event::SnpPage(1, 1, 0xaa),
+            Event::SnpPage(2, 1, 0xbb),
+            Event::SnpPage(3, 6, 0xaa),
+            Event::SnpFinish(false, [7; 32]),
+            Event::Decommission(handle),
+        ]
+    );
+}
+
+#[test]
+fn snp_vm() {
+    // Only a default VM is given to a launch, but it has no private memory.
+    assert!(Launch::new_snp(vm()).is_err());
+
+    // Not every host can create SNP VMs.
+    let kvm = Kvm::open().unwrap();
+    let mut vm = match VirtualMachine::new_snp(&kvm) {
+        Ok(vm) => vm,
+        Err(_) => return,
+    };
+
+    vm.add_region(0, MemoryFlags::default(), 0, memory(0x4000))
+        .unwrap();
+    Launch::new_snp(vm).unwrap();
+}
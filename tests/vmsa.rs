@@ -0,0 +1,76 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ketuvim::arch::{Registers, Segment, SpecialRegisters};
+use ketuvim::sev::{Vmsa, VmsaSegment};
+
+fn u64_at(page: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&page[offset..][..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[test]
+fn layout() {
+    let regs = Registers {
+        rax: 0x1111,
+        rdx: 0x2222,
+        r15: 0x3333,
+        rip: 0xfff0,
+        rflags: 0x2,
+        ..Default::default()
+    };
+
+    let mut sregs = SpecialRegisters {
+        cs: Segment::code64(0x08),
+        cr0: 0x6000_0011,
+        efer: 0x500,
+        ..Default::default()
+    };
+    sregs.gdt.base = 0x1000;
+    sregs.gdt.limit = 0x17;
+
+    let vmsa = Vmsa::new(&regs, &sregs);
+    let page = vmsa.as_bytes();
+    assert_eq!(page.len(), Vmsa::SIZE);
+
+    assert_eq!(&page[0x10..0x14], &[0x08, 0x00, 0x9b, 0x0a]);
+    assert_eq!(u64_at(page, 0x60 + 8), 0x1000);
+    assert_eq!(u64_at(page, 0xd0), 0x1500);
+    assert_eq!(u64_at(page, 0x148), 0x40);
+    assert_eq!(u64_at(page, 0x158), 0x11);
+    assert_eq!(u64_at(page, 0x160), 0x400);
+    assert_eq!(u64_at(page, 0x170), 0x2);
+    assert_eq!(u64_at(page, 0x178), 0xfff0);
+    assert_eq!(u64_at(page, 0x1f8), 0x1111);
+    assert_eq!(u64_at(page, 0x268), 0x0007_0406_0007_0406);
+    assert_eq!(u64_at(page, 0x310), 0x2222);
+    assert_eq!(u64_at(page, 0x378), 0x3333);
+    assert_eq!(u64_at(page, 0x3e8), 1);
+    assert_eq!(&page[0x408..0x40c], &0x1f80u32.to_le_bytes());
+    assert_eq!(&page[0x410..0x412], &0x37fu16.to_le_bytes());
+}
+
+#[test]
+fn unusable() {
+    let seg = Segment {
+        present: 1,
+        unusable: 1,
+        kind: 0x3,
+        s: 1,
+        ..Default::default()
+    };
+
+    assert_eq!(VmsaSegment::from(&seg).attrib, 0x13);
+}